@@ -1,10 +1,14 @@
-use std::{error::Error, fs::File, io::{self, BufRead, BufReader}, num::NonZeroUsize, ops::Range};
+use std::{error::Error, fs::{self, File}, io::{self, BufRead, BufReader, Stdout}, num::NonZeroUsize, ops::Range};
 
 use clap::{App, Arg};
-use csv::{ReaderBuilder, StringRecord, WriterBuilder};
+use csv::{QuoteStyle, ReaderBuilder, StringRecord, Writer, WriterBuilder};
 use regex::Regex;
+use serde_json::{Map, Value};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
 use crate::Extract::*;
+use crate::Mode::*;
 
 type MyResult<T> = Result<T, Box<dyn Error>>;
 type PositionList = Vec<Range<usize>>;
@@ -13,14 +17,68 @@ type PositionList = Vec<Range<usize>>;
 pub enum Extract {
     Fields(PositionList),
     Bytes(PositionList),
-    Chars(PositionList)
+    Chars(PositionList),
+    Graphemes(PositionList),
+    Columns(PositionList),
+    Layout(Vec<LayoutColumn>),
+    Json(Vec<JsonPath>)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayoutColumn {
+    name: String,
+    pos: Range<usize>,
+    trim: bool
+}
+
+#[derive(Debug, PartialEq)]
+pub struct JsonPath {
+    name: String,
+    pointer: String
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JsonFallback {
+    Empty,
+    Json,
+    Skip,
+    Error
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Format {
+    Tsv,
+    Csv,
+    Jsonl
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Mode {
+    Raw,
+    Csv
+}
+
+#[derive(Debug)]
+pub struct CsvOptions {
+    quote: u8,
+    escape: Option<u8>,
+    flexible: bool,
+    quote_style: QuoteStyle
 }
 
 #[derive(Debug)]
 pub struct Config {
     files: Vec<String>,
     delimiter: u8,
-    extract: Extract
+    extract: Extract,
+    mode: Mode,
+    only_delimited: bool,
+    no_split: bool,
+    format: Format,
+    header: bool,
+    missing: JsonFallback,
+    non_scalar: JsonFallback,
+    csv: CsvOptions
 }
 
 pub fn get_args() -> MyResult<Config> {
@@ -30,49 +88,159 @@ pub fn get_args() -> MyResult<Config> {
         .about("Rust cut")
         .arg(Arg::with_name("files").value_name("FILE").help("Input file(s)").multiple(true).default_value("-"))
         .arg(Arg::with_name("delimiter").value_name("DELIMITER").short("d").long("delim").help("Field delimiter").default_value("\t"))
-        .arg(Arg::with_name("fields").value_name("FIELDS").short("f").long("fields").help("Selected fields").conflicts_with_all(&["chars", "bytes"]))
-        .arg(Arg::with_name("bytes").value_name("BYTES").short("b").long("bytes").help("Selected bytes").conflicts_with_all(&["fields", "chars"]))
-        .arg(Arg::with_name("chars").value_name("CHARS").short("c").long("chars").help("Selected characters").conflicts_with_all(&["fields", "bytes"]))
+        .arg(Arg::with_name("fields").value_name("FIELDS").short("f").long("fields").help("Selected fields (or layout columns, or JSON paths)").conflicts_with_all(&["chars", "bytes", "graphemes", "columns"]))
+        .arg(Arg::with_name("bytes").value_name("BYTES").short("b").long("bytes").help("Selected bytes").conflicts_with_all(&["fields", "chars", "graphemes", "columns"]))
+        .arg(Arg::with_name("chars").value_name("CHARS").short("c").long("chars").help("Selected characters").conflicts_with_all(&["fields", "bytes", "graphemes", "columns"]))
+        .arg(Arg::with_name("graphemes").value_name("GRAPHEMES").long("graphemes").help("Selected grapheme clusters").conflicts_with_all(&["fields", "bytes", "chars", "columns"]))
+        .arg(Arg::with_name("columns").value_name("COLUMNS").long("columns").help("Selected display columns").conflicts_with_all(&["fields", "bytes", "chars", "graphemes"]))
+        .arg(Arg::with_name("no_split").short("n").help("Do not split multibyte characters with --bytes").requires("bytes"))
+        .arg(Arg::with_name("layout").value_name("LAYOUT").long("layout").help("Fixed-width column layout file").conflicts_with_all(&["bytes", "chars", "graphemes", "columns"]))
+        .arg(Arg::with_name("jsonl").long("jsonl").help("Extract JSON Lines values by path").requires("fields").conflicts_with_all(&["bytes", "chars", "graphemes", "columns", "layout"]))
+        .arg(Arg::with_name("missing").value_name("MISSING").long("missing").help("Handling of missing JSON keys").possible_values(&["empty", "skip", "error"]).requires("jsonl"))
+        .arg(Arg::with_name("non_scalar").value_name("NON_SCALAR").long("non-scalar").help("Handling of JSON arrays and objects").possible_values(&["json", "empty", "skip", "error"]).requires("jsonl"))
+        .arg(Arg::with_name("format").value_name("FORMAT").long("format").help("Output format [default: tsv]").possible_values(&["tsv", "csv", "jsonl"]))
+        .arg(Arg::with_name("header").long("header").help("Print column names first"))
+        .arg(Arg::with_name("mode").value_name("MODE").long("mode").help("Field splitting mode").possible_values(&["raw", "csv"]).default_value("csv"))
+        .arg(Arg::with_name("only_delimited").short("s").long("only-delimited").help("Do not print lines not containing delimiters (--mode raw)").conflicts_with_all(&["chars", "bytes", "graphemes", "columns"]))
+        .arg(Arg::with_name("quote").value_name("QUOTE").long("quote").help("CSV quote character").default_value("\""))
+        .arg(Arg::with_name("escape").value_name("ESCAPE").long("escape").help("CSV escape character (default: doubled quotes)"))
+        .arg(Arg::with_name("flexible").long("flexible").help("Allow CSV records of varying length"))
+        .arg(Arg::with_name("quote_style").value_name("STYLE").long("quote-style").help("CSV output quoting").possible_values(&["always", "necessary", "never"]).default_value("necessary"))
         .get_matches();
 
-    let delimiter = matches.value_of("delimiter").unwrap();
-    let delim_bytes = delimiter.as_bytes();
-    if delim_bytes.len() != 1 {
-        return Err(From::from(format!("--delim \"{}\" must be a single byte", delimiter)))
-    }
-    let fields = matches.value_of("fields").map(parse_pos).transpose()?;
+    let delimiter = parse_byte("delim", matches.value_of("delimiter").unwrap())?;
+    let jsonl = matches.is_present("jsonl");
+    let fields = matches.value_of("fields").filter(|_| !jsonl).map(parse_pos).transpose()?;
     let bytes = matches.value_of("bytes").map(parse_pos).transpose()?;
     let chars = matches.value_of("chars").map(parse_pos).transpose()?;
-    let extract  = if let Some(field_pos) = fields {
+    let graphemes = matches.value_of("graphemes").map(parse_pos).transpose()?;
+    let columns = matches.value_of("columns").map(parse_pos).transpose()?;
+    let extract  = if jsonl {
+        Json(parse_json_paths(matches.value_of("fields").unwrap())?)
+    } else if let Some(layout) = matches.value_of("layout") {
+        let columns = parse_layout(&fs::read_to_string(layout).map_err(|e| format!("{}: {}", layout, e))?)?;
+        if let Some(range) = fields.iter().flatten().find(|range| range.end > columns.len()) {
+            return Err(From::from(format!("field {} out of range: layout has {} columns", range.end, columns.len())));
+        }
+        match fields {
+            Some(field_pos) => Layout(field_pos.into_iter().flat_map(|range| range.filter_map(|i| columns.get(i)).cloned()).collect()),
+            None => Layout(columns),
+        }
+    } else if let Some(field_pos) = fields {
         Fields(field_pos)
     } else if let Some(byte_pos) = bytes {
         Bytes(byte_pos)
     } else if let Some(char_pos) = chars {
         Chars(char_pos)
+    } else if let Some(grapheme_pos) = graphemes {
+        Graphemes(grapheme_pos)
+    } else if let Some(column_pos) = columns {
+        Columns(column_pos)
     } else {
-        return Err(From::from("Must have --fields, --bytes, or --chars"));
+        return Err(From::from("Must have --fields, --bytes, --chars, --graphemes, --columns, or --layout"));
+    };
+
+    let mode = match matches.value_of("mode") {
+        Some("raw") => Raw,
+        _ => Csv,
+    };
+    if mode == Csv && matches.is_present("only_delimited") {
+        return Err(From::from("--only-delimited requires --mode raw"));
+    }
+    if mode == Raw {
+        let csv_args = ["quote", "escape", "flexible", "quote_style"];
+        if let Some(name) = csv_args.iter().find(|name| matches.occurrences_of(name) > 0) {
+            return Err(From::from(format!("--{} requires --mode csv", name.replace('_', "-"))));
+        }
+    }
+    if !jsonl && !matches.is_present("layout") {
+        if let Some(name) = ["format", "header"].iter().find(|name| matches.is_present(name)) {
+            return Err(From::from(format!("--{} requires --layout or --jsonl", name)));
+        }
+    }
+    if jsonl && matches.value_of("format") == Some("jsonl") {
+        return Err(From::from("--jsonl supports only --format tsv or csv"));
+    }
+    let format = match matches.value_of("format") {
+        Some("csv") => Format::Csv,
+        Some("jsonl") => Format::Jsonl,
+        _ => Format::Tsv,
+    };
+    let quote_style = match matches.value_of("quote_style") {
+        Some("always") => QuoteStyle::Always,
+        Some("never") => QuoteStyle::Never,
+        _ => QuoteStyle::Necessary,
     };
 
     Ok(Config {
         files: matches.values_of_lossy("files").unwrap(),
-        delimiter: *delim_bytes.first().unwrap(),
+        delimiter,
         extract,
+        mode,
+        only_delimited: matches.is_present("only_delimited"),
+        no_split: matches.is_present("no_split"),
+        format,
+        header: matches.is_present("header"),
+        missing: parse_fallback(matches.value_of("missing").unwrap_or("empty")),
+        non_scalar: parse_fallback(matches.value_of("non_scalar").unwrap_or("json")),
+        csv: CsvOptions {
+            quote: parse_byte("quote", matches.value_of("quote").unwrap())?,
+            escape: matches.value_of("escape").map(|escape| parse_byte("escape", escape)).transpose()?,
+            flexible: matches.is_present("flexible"),
+            quote_style,
+        },
     })
 }
 
-pub fn run(config: Config) -> MyResult<()> {
+pub fn run(config: Config) -> MyResult<bool> {
+    let mut success = true;
     for filename in &config.files {
-        match open(&filename) {
-            Err(err) => eprintln!("{}: {}", filename, err),
+        match open(filename) {
+            Err(err) => {
+                eprintln!("{}: {}", filename, err);
+                success = false;
+            }
             Ok(file) => match &config.extract {
+                Fields(field_pos) if config.mode == Raw => {
+                    let delimiter = char::from(config.delimiter);
+                    for line in file.lines() {
+                        let line = line?;
+                        if !line.contains(delimiter) {
+                            if !config.only_delimited {
+                                println!("{}", line);
+                            }
+                            continue;
+                        }
+                        println!("{}", extract_split(&line, delimiter, field_pos).join(&delimiter.to_string()));
+                    }
+                }
                 Fields(field_pos) => {
-                    let mut reader = ReaderBuilder::new().delimiter(config.delimiter).has_headers(false).from_reader(file);
-                    let mut wtr = WriterBuilder::new().delimiter(config.delimiter).from_writer(io::stdout());
+                    let csv = &config.csv;
+                    let mut reader = ReaderBuilder::new()
+                        .delimiter(config.delimiter)
+                        .quote(csv.quote)
+                        .escape(csv.escape)
+                        .double_quote(csv.escape.is_none())
+                        .flexible(csv.flexible)
+                        .has_headers(false)
+                        .from_reader(file);
+                    let mut wtr = WriterBuilder::new()
+                        .delimiter(config.delimiter)
+                        .quote(csv.quote)
+                        .escape(csv.escape.unwrap_or(b'\\'))
+                        .double_quote(csv.escape.is_none())
+                        .quote_style(csv.quote_style)
+                        .from_writer(io::stdout());
                     for record in reader.records() {
                         let record = record?;
                         wtr.write_record(extract_fields(&record, field_pos))?;
                     }
                 }
+                Bytes(byte_pos) if config.no_split => {
+                    for line in file.lines() {
+                        println!("{}", extract_whole_chars(&line?, byte_pos));
+                    }
+                },
                 Bytes(byte_pos) => {
                     for line in file.lines() {
                         println!("{}", extract_bytes(&line?, byte_pos));
@@ -83,16 +251,126 @@ pub fn run(config: Config) -> MyResult<()> {
                         println!("{}", extract_chars(&line?, char_pos));
                     }
                 },
+                Graphemes(grapheme_pos) => {
+                    for line in file.lines() {
+                        println!("{}", extract_graphemes(&line?, grapheme_pos));
+                    }
+                },
+                Columns(column_pos) => {
+                    for line in file.lines() {
+                        println!("{}", extract_columns(&line?, column_pos));
+                    }
+                },
+                Layout(columns) if config.format == Format::Jsonl => {
+                    for line in file.lines() {
+                        let values = extract_layout(&line?, columns);
+                        let object = columns.iter().zip(values).map(|(col, value)| (col.name.clone(), Value::String(value))).collect::<Map<_, _>>();
+                        println!("{}", Value::Object(object));
+                    }
+                },
+                Layout(columns) => {
+                    let mut wtr = format_writer(&config);
+                    if config.header {
+                        wtr.write_record(columns.iter().map(|col| &col.name))?;
+                    }
+                    for line in file.lines() {
+                        wtr.write_record(extract_layout(&line?, columns))?;
+                    }
+                },
+                Json(paths) => {
+                    let mut wtr = format_writer(&config);
+                    if config.header {
+                        wtr.write_record(paths.iter().map(|path| &path.name))?;
+                    }
+                    for (i, line) in file.lines().enumerate() {
+                        let line = line?;
+                        if line.trim().is_empty() {
+                            continue;
+                        }
+                        // A bad line is reported and skipped, like a file that cannot be opened.
+                        let values = serde_json::from_str::<Value>(&line)
+                            .map_err(|e| e.to_string())
+                            .and_then(|value| extract_json(&value, paths, config.missing, config.non_scalar));
+                        match values {
+                            Err(e) => {
+                                wtr.flush()?;
+                                eprintln!("{}: line {}: {}", filename, i + 1, e);
+                                success = false;
+                            }
+                            Ok(Some(values)) => wtr.write_record(values)?,
+                            Ok(None) => {}
+                        }
+                    }
+                },
             }
         }
     }
-    Ok(())
+    Ok(success)
+}
+
+fn parse_byte(name: &str, value: &str) -> MyResult<u8> {
+    match value.as_bytes() {
+        [byte] => Ok(*byte),
+        _ => Err(From::from(format!("--{} \"{}\" must be a single byte", name, value))),
+    }
+}
+
+fn parse_layout(spec: &str) -> MyResult<Vec<LayoutColumn>> {
+    let mut columns = vec![];
+    for (i, line) in spec.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let layout_error = |msg: &str| format!("layout line {}: {}", i + 1, msg);
+        let parts = line.split(',').map(str::trim).collect::<Vec<_>>();
+        if parts.len() < 3 || parts.len() > 4 {
+            return Err(From::from(layout_error("expected \"name,start,width[,trim]\"")));
+        }
+        let start = parse_index(parts[1]).map_err(|e| layout_error(&e))?;
+        let width = parts[2].parse::<NonZeroUsize>().map_err(|_| layout_error(&format!("illegal width: \"{}\"", parts[2])))?;
+        let trim = match parts.get(3) {
+            None | Some(&"") | Some(&"false") => false,
+            Some(&"true") => true,
+            Some(value) => return Err(From::from(layout_error(&format!("illegal trim value: \"{}\"", value)))),
+        };
+        columns.push(LayoutColumn { name: parts[0].to_string(), pos: start..start + usize::from(width), trim });
+    }
+    if columns.is_empty() {
+        return Err(From::from("layout has no columns"));
+    }
+    Ok(columns)
+}
+
+fn parse_json_paths(paths: &str) -> MyResult<Vec<JsonPath>> {
+    paths.split(',')
+         .map(|path| {
+             if path.is_empty() {
+                 return Err(format!("illegal JSON path: \"{}\"", path));
+             }
+             let pointer = if path.starts_with('/') {
+                 path.to_string()
+             } else {
+                 path.split('.').map(|key| format!("/{}", key.replace('~', "~0").replace('/', "~1"))).collect()
+             };
+             Ok(JsonPath { name: path.to_string(), pointer })
+         })
+         .collect::<Result<_, _>>()
+         .map_err(From::from)
+}
+
+fn parse_fallback(value: &str) -> JsonFallback {
+    match value {
+        "json" => JsonFallback::Json,
+        "skip" => JsonFallback::Skip,
+        "error" => JsonFallback::Error,
+        _ => JsonFallback::Empty,
+    }
 }
 
 fn parse_pos(range: &str) -> MyResult<PositionList> {
     let range_re = Regex::new(r"^(\d+)-(\d+)$").unwrap();
     range.split(',')
-         .into_iter()
          .map(|val| {
              parse_index(val).map(|n| n..n+1).or_else(|e| {
                  range_re.captures(val).ok_or(e).and_then(|captures| {
@@ -111,13 +389,17 @@ fn parse_pos(range: &str) -> MyResult<PositionList> {
 
 fn parse_index(input: &str) -> Result<usize, String> {
     let value_error = || format!("illegal list value: \"{}\"", input);
-    input.starts_with("+")
-         .then(|| Err(value_error()))
-         .unwrap_or_else(|| {
-            input.parse::<NonZeroUsize>()
-                 .map(|n| usize::from(n)-1)
-                 .map_err(|_| value_error())
-        })
+    if input.starts_with('+') {
+        return Err(value_error());
+    }
+    input.parse::<NonZeroUsize>()
+         .map(|n| usize::from(n)-1)
+         .map_err(|_| value_error())
+}
+
+fn format_writer(config: &Config) -> Writer<Stdout> {
+    let delimiter = if config.format == Format::Csv { b',' } else { b'\t' };
+    WriterBuilder::new().delimiter(delimiter).quote_style(config.csv.quote_style).from_writer(io::stdout())
 }
 
 fn open(filename: &str) -> MyResult<Box<dyn BufRead>> {
@@ -138,16 +420,70 @@ fn extract_bytes(line: &str, byte_pos: &[Range<usize>]) -> String {
     String::from_utf8_lossy(&selected).into_owned()
 }
 
+fn extract_whole_chars(line: &str, byte_pos: &[Range<usize>]) -> String {
+    let chars = line.char_indices().map(|(i, c)| (i + c.len_utf8() - 1, c)).collect::<Vec<_>>();
+    byte_pos.iter().flat_map(|range| chars.iter().filter(move |(last, _)| range.contains(last)).map(|(_, c)| c)).collect()
+}
+
+fn extract_graphemes(line: &str, grapheme_pos: &[Range<usize>]) -> String {
+    let graphemes = line.graphemes(true).collect::<Vec<_>>();
+    grapheme_pos.iter().cloned().flat_map(|range| range.filter_map(|i| graphemes.get(i)).copied()).collect()
+}
+
+fn extract_columns(line: &str, column_pos: &[Range<usize>]) -> String {
+    let mut column = 0;
+    let graphemes = line.graphemes(true).map(|g| {
+        let width = g.width();
+        let last = column + width.max(1) - 1;
+        column += width;
+        (last, g)
+    }).collect::<Vec<_>>();
+    column_pos.iter().flat_map(|range| graphemes.iter().filter(move |(last, _)| range.contains(last)).map(|(_, g)| *g)).collect()
+}
+
+fn extract_layout(line: &str, columns: &[LayoutColumn]) -> Vec<String> {
+    columns.iter().map(|col| {
+        let value = extract_chars(line, std::slice::from_ref(&col.pos));
+        if col.trim { value.trim().to_string() } else { value }
+    }).collect()
+}
+
+fn extract_json(value: &Value, paths: &[JsonPath], missing: JsonFallback, non_scalar: JsonFallback) -> Result<Option<Vec<String>>, String> {
+    let mut values = vec![];
+    for path in paths {
+        let found = match value.pointer(&path.pointer) {
+            None => Err((missing, format!("missing key \"{}\"", path.name))),
+            Some(Value::Null) => Ok(String::new()),
+            Some(Value::String(s)) => Ok(s.clone()),
+            Some(Value::Array(_) | Value::Object(_)) if non_scalar != JsonFallback::Json => Err((non_scalar, format!("non-scalar value at \"{}\"", path.name))),
+            Some(v) => Ok(v.to_string()),
+        };
+        match found {
+            Ok(s) => values.push(s),
+            Err((JsonFallback::Skip, _)) => return Ok(None),
+            Err((JsonFallback::Error, msg)) => return Err(msg),
+            Err(_) => values.push(String::new()),
+        }
+    }
+    Ok(Some(values))
+}
+
 fn extract_fields<'a>(record: &'a StringRecord, field_pos: &[Range<usize>]) -> Vec<&'a str> {
     field_pos.iter().cloned().flat_map(|range| range.filter_map(|i| record.get(i))).collect()
 }
 
+fn extract_split<'a>(line: &'a str, delimiter: char, field_pos: &[Range<usize>]) -> Vec<&'a str> {
+    let fields = line.split(delimiter).collect::<Vec<_>>();
+    field_pos.iter().cloned().flat_map(|range| range.filter_map(|i| fields.get(i)).copied()).collect()
+}
+
 
 #[cfg(test)]
+#[allow(clippy::single_range_in_vec_init)]
 mod unit_test {
     use csv::StringRecord;
 
-    use crate::{extract_bytes, extract_chars, extract_fields, parse_pos};
+    use crate::{extract_bytes, extract_chars, extract_columns, extract_fields, extract_graphemes, extract_json, extract_layout, extract_split, extract_whole_chars, parse_json_paths, parse_layout, parse_pos, JsonFallback, JsonPath, LayoutColumn};
 
 
     #[test]
@@ -238,6 +574,76 @@ mod unit_test {
         assert_eq!(res.unwrap(), vec![14..15, 18..20]);
     }
 
+    #[test]
+    fn test_parse_layout() {
+        let res = parse_layout("");
+        assert!(res.is_err());
+        assert_eq!(res.unwrap_err().to_string(), "layout has no columns");
+
+        let res = parse_layout("id,1");
+        assert!(res.is_err());
+        assert_eq!(res.unwrap_err().to_string(), "layout line 1: expected \"name,start,width[,trim]\"");
+
+        let res = parse_layout("# comment\nid,0,5");
+        assert!(res.is_err());
+        assert_eq!(res.unwrap_err().to_string(), "layout line 2: illegal list value: \"0\"");
+
+        let res = parse_layout("id,1,0");
+        assert!(res.is_err());
+        assert_eq!(res.unwrap_err().to_string(), "layout line 1: illegal width: \"0\"");
+
+        let res = parse_layout("id,1,5,yes");
+        assert!(res.is_err());
+        assert_eq!(res.unwrap_err().to_string(), "layout line 1: illegal trim value: \"yes\"");
+
+        let res = parse_layout("# name,start,width,trim\nid, 1, 5\nname,6,10,true\n");
+        assert!(res.is_ok());
+        assert_eq!(res.unwrap(), vec![
+            LayoutColumn { name: "id".to_string(), pos: 0..5, trim: false },
+            LayoutColumn { name: "name".to_string(), pos: 5..15, trim: true },
+        ]);
+    }
+
+    #[test]
+    fn test_extract_layout() {
+        let columns = vec![
+            LayoutColumn { name: "id".to_string(), pos: 0..3, trim: false },
+            LayoutColumn { name: "name".to_string(), pos: 3..9, trim: true },
+        ];
+        assert_eq!(extract_layout("001 Sham  ", &columns), &["001", "Sham"]);
+        assert_eq!(extract_layout("01", &columns), &["01", ""]);
+    }
+
+    #[test]
+    fn test_parse_json_paths() {
+        let res = parse_json_paths("user.id,");
+        assert!(res.is_err());
+        assert_eq!(res.unwrap_err().to_string(), "illegal JSON path: \"\"");
+
+        let res = parse_json_paths("user.id,/request/path,a~b.c/d");
+        assert!(res.is_ok());
+        assert_eq!(res.unwrap(), vec![
+            JsonPath { name: "user.id".to_string(), pointer: "/user/id".to_string() },
+            JsonPath { name: "/request/path".to_string(), pointer: "/request/path".to_string() },
+            JsonPath { name: "a~b.c/d".to_string(), pointer: "/a~0b/c~1d".to_string() },
+        ]);
+    }
+
+    #[test]
+    fn test_extract_json() {
+        let value = serde_json::json!({"user": {"id": 7, "name": "Sham", "tags": ["a", "b"]}, "ok": true, "note": null});
+        let paths = parse_json_paths("user.name,user.id,ok,note,user.tags.1").unwrap();
+        assert_eq!(extract_json(&value, &paths, JsonFallback::Error, JsonFallback::Error), Ok(Some(vec!["Sham".to_string(), "7".to_string(), "true".to_string(), "".to_string(), "b".to_string()])));
+
+        let paths = parse_json_paths("user.id,user.tags,user.email").unwrap();
+        assert_eq!(extract_json(&value, &paths, JsonFallback::Empty, JsonFallback::Json), Ok(Some(vec!["7".to_string(), "[\"a\",\"b\"]".to_string(), "".to_string()])));
+        assert_eq!(extract_json(&value, &paths, JsonFallback::Empty, JsonFallback::Empty), Ok(Some(vec!["7".to_string(), "".to_string(), "".to_string()])));
+        assert_eq!(extract_json(&value, &paths, JsonFallback::Skip, JsonFallback::Json), Ok(None));
+        assert_eq!(extract_json(&value, &paths, JsonFallback::Empty, JsonFallback::Skip), Ok(None));
+        assert_eq!(extract_json(&value, &paths, JsonFallback::Error, JsonFallback::Json), Err("missing key \"user.email\"".to_string()));
+        assert_eq!(extract_json(&value, &paths, JsonFallback::Empty, JsonFallback::Error), Err("non-scalar value at \"user.tags\"".to_string()));
+    }
+
     #[test]
     fn test_extract_chars() {
         assert_eq!(extract_chars("", &[0..1]), "".to_string());
@@ -258,6 +664,34 @@ mod unit_test {
         assert_eq!(extract_bytes("ábc", &[0..2, 5..6]), "á".to_string());
     }
 
+    #[test]
+    fn test_extract_whole_chars() {
+        assert_eq!(extract_whole_chars("ábc", &[0..1]), "".to_string());
+        assert_eq!(extract_whole_chars("ábc", &[1..2]), "á".to_string());
+        assert_eq!(extract_whole_chars("ábc", &[0..3]), "áb".to_string());
+        assert_eq!(extract_whole_chars("ábc", &[3..4, 2..3]), "cb".to_string());
+        assert_eq!(extract_whole_chars("ábc", &[0..2, 5..6]), "á".to_string());
+    }
+
+    #[test]
+    fn test_extract_graphemes() {
+        assert_eq!(extract_graphemes("", &[0..1]), "".to_string());
+        assert_eq!(extract_graphemes("a\u{301}bc", &[0..1]), "a\u{301}".to_string());
+        assert_eq!(extract_graphemes("👍🏽x", &[0..1]), "👍🏽".to_string());
+        assert_eq!(extract_graphemes("👍🏽x", &[1..2, 0..1]), "x👍🏽".to_string());
+        assert_eq!(extract_graphemes("a\u{301}bc", &[0..1, 4..5]), "a\u{301}".to_string());
+    }
+
+    #[test]
+    fn test_extract_columns() {
+        assert_eq!(extract_columns("", &[0..1]), "".to_string());
+        assert_eq!(extract_columns("日本語", &[0..1]), "".to_string());
+        assert_eq!(extract_columns("日本語", &[0..2]), "日".to_string());
+        assert_eq!(extract_columns("日本語", &[1..4]), "日本".to_string());
+        assert_eq!(extract_columns("a\u{301}b", &[0..1]), "a\u{301}".to_string());
+        assert_eq!(extract_columns("a日b", &[3..4, 0..1]), "ba".to_string());
+    }
+
     #[test]
     fn test_extract_fields() {
         let rec = StringRecord::from(vec!["Captain", "Sham", "12345"]);
@@ -267,4 +701,13 @@ mod unit_test {
         assert_eq!(extract_fields(&rec, &[0..1, 3..4]), &["Captain"]);
         assert_eq!(extract_fields(&rec, &[1..2, 0..1]), &["Sham", "Captain"]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_extract_split() {
+        assert_eq!(extract_split("Captain\tSham\t12345", '\t', &[0..1]), &["Captain"]);
+        assert_eq!(extract_split("Captain\tSham\t12345", '\t', &[0..1, 2..3]), &["Captain", "12345"]);
+        assert_eq!(extract_split("Captain\tSham\t12345", '\t', &[1..2, 3..4]), &["Sham"]);
+        assert_eq!(extract_split("\"Captain\t\"Sham", '\t', &[0..2]), &["\"Captain", "\"Sham"]);
+        assert_eq!(extract_split("a,,b", ',', &[1..3]), &["", "b"]);
+    }
+}
@@ -1,3 +1,5 @@
+#![allow(clippy::needless_borrows_for_generic_args)]
+
 use assert_cmd::Command;
 use predicates::prelude::*;
 use rand::{distributions::Alphanumeric, Rng};
@@ -9,6 +11,12 @@ const PRG: &str = "cutr";
 const CSV: &str = "tests/inputs/movies1.csv";
 const TSV: &str = "tests/inputs/movies1.tsv";
 const BOOKS: &str = "tests/inputs/books.tsv";
+const QUOTES: &str = "tests/inputs/quotes.tsv";
+const QUOTED: &str = "tests/inputs/quoted.csv";
+const UNICODE: &str = "tests/inputs/unicode.txt";
+const REPORT: &str = "tests/inputs/report.txt";
+const LAYOUT: &str = "tests/inputs/report.layout";
+const LOGS: &str = "tests/inputs/logs.jsonl";
 
 // --------------------------------------------------
 fn random_string() -> String {
@@ -37,7 +45,7 @@ fn skips_bad_file() -> TestResult {
     Command::cargo_bin(PRG)?
         .args(&["-f", "1", CSV, &bad, TSV])
         .assert()
-        .success()
+        .code(1)
         .stderr(predicate::str::is_match(expected)?);
     Ok(())
 }
@@ -55,7 +63,7 @@ fn dies(args: &[&str], expected: &str) -> TestResult {
 // --------------------------------------------------
 #[test]
 fn dies_not_enough_args() -> TestResult {
-    dies(&[CSV], "Must have --fields, --bytes, or --chars")
+    dies(&[CSV], "Must have --fields, --bytes, --chars, --graphemes, --columns, or --layout")
 }
 
 // --------------------------------------------------
@@ -337,3 +345,237 @@ fn tsv_c1_8() -> TestResult {
 fn repeated_value() -> TestResult {
     run(&[BOOKS, "-c", "1,1"], "tests/expected/books.c1,1.out")
 }
+
+// --------------------------------------------------
+#[test]
+fn dies_raw_csv_option() -> TestResult {
+    dies(
+        &[QUOTES, "-f", "1", "--mode", "raw", "--quote-style", "always"],
+        "--quote-style requires --mode csv",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_only_delimited_chars() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([QUOTES, "-c", "1", "-s"])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn raw_f2() -> TestResult {
+    run(
+        &[QUOTES, "-f", "2", "--mode", "raw"],
+        "tests/expected/quotes.tsv.f2.raw.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn raw_only_delimited() -> TestResult {
+    run(
+        &[QUOTES, "-f", "1,3", "--mode", "raw", "-s"],
+        "tests/expected/quotes.tsv.f1,3.raw.s.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn only_delimited_mixed_lines() -> TestResult {
+    let input = "a,b\nnodelim\nc,d\n";
+    Command::cargo_bin(PRG)?
+        .args(&["-", "-d", ",", "-f", "1", "-s"])
+        .write_stdin(input)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--only-delimited requires --mode raw"));
+    Command::cargo_bin(PRG)?
+        .args(&["-", "-d", ",", "-f", "1", "-s", "--mode", "raw"])
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout("a\nc\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn csv_quote_style_always() -> TestResult {
+    run(
+        &[QUOTED, "-f", "1", "-d", ",", "--quote-style", "always"],
+        "tests/expected/quoted.csv.f1.dcomma.always.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn csv_quote_style_never() -> TestResult {
+    run(
+        &[QUOTED, "-f", "1", "-d", ",", "--quote-style", "never"],
+        "tests/expected/quoted.csv.f1.dcomma.never.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn csv_quote_char() -> TestResult {
+    run(
+        &["tests/inputs/squote.csv", "-f", "1", "-d", ",", "--quote", "'"],
+        "tests/expected/squote.csv.f1.dcomma.quote.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_no_split_without_bytes() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([UNICODE, "-c", "1", "-n"])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn unicode_graphemes() -> TestResult {
+    run(
+        &[UNICODE, "--graphemes", "4-6"],
+        "tests/expected/unicode.txt.graphemes4-6.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn unicode_columns() -> TestResult {
+    run(
+        &[UNICODE, "--columns", "1-5"],
+        "tests/expected/unicode.txt.columns1-5.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn unicode_no_split_bytes() -> TestResult {
+    run(
+        &[UNICODE, "-n", "-b", "1-5"],
+        "tests/expected/unicode.txt.n.b1-5.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_format_without_layout() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([TSV, "-f", "1", "--format", "csv"])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_layout_file() -> TestResult {
+    let bad = gen_bad_file();
+    let expected = format!("{}: .* [(]os error 2[)]", bad);
+    Command::cargo_bin(PRG)?
+        .args([REPORT, "--layout", &bad])
+        .assert()
+        .failure()
+        .stderr(predicate::str::is_match(expected)?);
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_layout_field_out_of_range() -> TestResult {
+    dies(&[REPORT, "--layout", LAYOUT, "-f", "2-5"], "field 5 out of range: layout has 3 columns")
+}
+
+// --------------------------------------------------
+#[test]
+fn layout_tsv() -> TestResult {
+    run(
+        &[REPORT, "--layout", LAYOUT],
+        "tests/expected/report.txt.layout.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn layout_fields_csv_header() -> TestResult {
+    run(
+        &[REPORT, "--layout", LAYOUT, "-f", "3,2", "--format", "csv", "--header"],
+        "tests/expected/report.txt.layout.f3,2.csv.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn layout_jsonl() -> TestResult {
+    run(
+        &[REPORT, "--layout", LAYOUT, "--format", "jsonl"],
+        "tests/expected/report.txt.layout.jsonl.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_jsonl_missing_key() -> TestResult {
+    dies(
+        &[LOGS, "--jsonl", "-f", "user.name", "--missing", "error"],
+        "line 2: missing key \"user.name\"",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_jsonl_non_scalar() -> TestResult {
+    dies(
+        &[LOGS, "--jsonl", "-f", "request.headers", "--non-scalar", "error"],
+        "line 1: non-scalar value at \"request.headers\"",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn jsonl_bad_line_continues() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(&["-", LOGS, "--jsonl", "-f", "user.id"])
+        .write_stdin("{\"user\": {\"id\": 9}}\nnot json\n{\"user\": {\"id\": 10}}\n")
+        .assert()
+        .failure()
+        .stdout("9\n10\n1\n2\n3\n")
+        .stderr(predicate::str::starts_with("-: line 2: expected"));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn jsonl_paths() -> TestResult {
+    run(
+        &[LOGS, "--jsonl", "-f", "user.id,request.path"],
+        "tests/expected/logs.jsonl.f.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn jsonl_pointer_csv_skip_missing() -> TestResult {
+    run(
+        &[LOGS, "--jsonl", "-f", "/user/name,user.id", "--format", "csv", "--header", "--missing", "skip"],
+        "tests/expected/logs.jsonl.f.csv.skip.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn jsonl_nested() -> TestResult {
+    run(
+        &[LOGS, "--jsonl", "-f", "user.id,request.headers"],
+        "tests/expected/logs.jsonl.f.nested.out",
+    )
+}
@@ -0,0 +1,101 @@
+use std::{collections::{BTreeMap, HashMap}, fs::File, io::{self, Read}, path::{Path, PathBuf}};
+
+use crate::Context;
+
+// Bytes hashed from the start of each file before whole files are hashed.
+const PARTIAL_BYTES: u64 = 4096;
+
+pub struct Candidate {
+    pub path: PathBuf,
+    pub size: u64,
+    pub inode: Option<(u64, u64)>
+}
+
+pub struct Group {
+    pub size: u64,
+    pub hash: String,
+    pub files: usize,
+    pub paths: Vec<PathBuf>
+}
+
+// Hardlinks to one inode are a single file: its contents are hashed once
+// and it only counts as a duplicate of files with other inodes.
+struct Inode {
+    size: u64,
+    paths: Vec<PathBuf>
+}
+
+// Narrows candidates down by size, then by a hash of their first
+// PARTIAL_BYTES, then by a hash of their whole contents, so most files are
+// never read completely. Empty files are skipped as there is nothing to
+// reclaim from them, and files that cannot be read are reported through
+// `ctx` so that the scan exits non-zero.
+pub fn find(candidates: Vec<Candidate>, ctx: &Context) -> Vec<Group> {
+    let mut inodes: Vec<Inode> = vec![];
+    let mut seen: HashMap<(u64, u64), usize> = HashMap::new();
+    for candidate in candidates.into_iter().filter(|candidate| candidate.size > 0) {
+        match candidate.inode.and_then(|inode| seen.get(&inode)) {
+            Some(&i) => {
+                let paths = &mut inodes[i].paths;
+                if !paths.contains(&candidate.path) {
+                    paths.push(candidate.path);
+                }
+            }
+            None => {
+                if let Some(inode) = candidate.inode {
+                    seen.insert(inode, inodes.len());
+                }
+                inodes.push(Inode { size: candidate.size, paths: vec![candidate.path] });
+            }
+        }
+    }
+
+    let mut groups = vec![];
+    for (size, same_size) in group_by(inodes, |inode| Some(inode.size)) {
+        for (partial, same_start) in group_by(same_size, |inode| hash(&inode.paths[0], Some(PARTIAL_BYTES), ctx)) {
+            let same_contents = if size <= PARTIAL_BYTES {
+                vec![(partial, same_start)]
+            } else {
+                group_by(same_start, |inode| hash(&inode.paths[0], None, ctx))
+            };
+            for (hash, inodes) in same_contents {
+                let files = inodes.len();
+                let mut paths = inodes.into_iter().flat_map(|inode| inode.paths).collect::<Vec<_>>();
+                paths.sort();
+                groups.push(Group { size, hash: blake3::Hash::from(hash).to_hex().to_string(), files, paths });
+            }
+        }
+    }
+    groups.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.paths.cmp(&b.paths)));
+    groups
+}
+
+// Keeps only the groups of two or more inodes; `key` returns None for files
+// that could not be read, which are dropped.
+fn group_by<K: Ord>(inodes: Vec<Inode>, key: impl Fn(&Inode) -> Option<K>) -> Vec<(K, Vec<Inode>)> {
+    let mut groups: BTreeMap<K, Vec<Inode>> = BTreeMap::new();
+    for inode in inodes {
+        if let Some(key) = key(&inode) {
+            groups.entry(key).or_default().push(inode);
+        }
+    }
+    groups.into_iter().filter(|(_, inodes)| inodes.len() > 1).collect()
+}
+
+fn hash(path: &Path, limit: Option<u64>, ctx: &Context) -> Option<[u8; 32]> {
+    let result = File::open(path).and_then(|file| {
+        let mut hasher = blake3::Hasher::new();
+        match limit {
+            Some(limit) => io::copy(&mut file.take(limit), &mut hasher)?,
+            None => io::copy(&mut &file, &mut hasher)?,
+        };
+        Ok(*hasher.finalize().as_bytes())
+    });
+    match result {
+        Ok(hash) => Some(hash),
+        Err(e) => {
+            ctx.fail(format!("{}: {}", path.display(), e));
+            None
+        }
+    }
+}
@@ -1,13 +1,30 @@
-use std::error::Error;
+mod duplicates;
+mod mime;
+
+use std::{cell::{Cell, OnceCell, RefCell}, collections::BTreeMap, env, error::Error, ffi::OsStr, fs::{self, File, FileType, Metadata}, io::{self, BufRead, BufReader, BufWriter, IsTerminal, Read, Stdout, Write}, path::{Path, PathBuf}, process::Command, sync::{atomic::{AtomicBool, Ordering}, mpsc, Mutex}, thread, time::{SystemTime, UNIX_EPOCH}};
 
 use clap::{App, Arg};
-use regex::Regex;
-use walkdir::{DirEntry, WalkDir};
+use globset::{GlobBuilder, GlobMatcher};
+use ignore::{WalkBuilder, WalkState};
+use regex::{Regex, RegexBuilder};
+use serde_json::json;
+use walkdir::WalkDir;
 
 use crate::EntryType::*;
 
 type MyResult<T> = Result<T, Box<dyn Error>>;
 
+// Upper bound on the bytes of paths passed to one `-exec ... {} +` command.
+const EXEC_BATCH_BYTES: usize = 128 * 1024;
+
+// Bytes read from the start of a file for -binary, -text and -mime.
+const HEAD_BYTES: usize = 8192;
+
+const VALUE_PRIMARIES: [&str; 18] = ["name", "iname", "path", "ipath", "regex", "iregex", "type", "size", "mtime", "mmin", "atime", "amin", "newer", "perm", "user", "group", "contains", "mime"];
+
+// Primaries that are also accepted as --flags ahead of the expression.
+const VALUE_FLAGS: [&str; 9] = ["size", "mtime", "mmin", "atime", "amin", "newer", "perm", "user", "group"];
+
 #[derive(Debug, Eq, PartialEq)]
 enum EntryType {
     Dir,
@@ -15,71 +32,1051 @@ enum EntryType {
     Link
 }
 
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Comparison {
+    Less(i64),
+    Exactly(i64),
+    More(i64)
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum PermMatch {
+    Exact,
+    All,
+    Any
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Access {
+    Read,
+    Write,
+    Execute
+}
+
+#[derive(Debug)]
+enum Predicate {
+    Name(GlobMatcher),
+    Path(GlobMatcher),
+    Regex(Regex),
+    Type(EntryType),
+    Size(Comparison, u64),
+    Modified(Comparison, u64),
+    Accessed(Comparison, u64),
+    Newer(SystemTime),
+    Perm(PermMatch, u32),
+    User(u32),
+    Group(u32),
+    Empty,
+    Access(Access),
+    Contains(regex::bytes::Regex),
+    Binary,
+    Text,
+    Mime(GlobMatcher),
+    Prune
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum Directive {
+    Literal(String),
+    Path,
+    Size,
+    Modified,
+    Mode,
+    User
+}
+
+#[derive(Debug)]
+enum Action {
+    Print,
+    Print0,
+    Printf(Vec<Directive>),
+    Delete,
+    Exec(Vec<String>),
+    ExecBatch(Vec<String>, Mutex<Vec<String>>)
+}
+
+// And(vec![]) is always true and Or(vec![]) always false, which is how
+// -true and -false are represented.
+#[derive(Debug)]
+enum Expr {
+    Test(Predicate),
+    Action(Action),
+    Not(Box<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>)
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Follow {
+    Never,
+    Roots,
+    Always
+}
+
+#[derive(Debug)]
+struct WalkOptions {
+    min_depth: usize,
+    max_depth: Option<usize>,
+    follow: Follow,
+    same_file_system: bool,
+    contents_first: bool,
+    sort: bool,
+    threads: usize
+}
+
 #[derive(Debug)]
 pub struct Config {
     paths: Vec<String>,
-    names: Vec<Regex>,
-    entry_types: Vec<EntryType>
+    expr: Expr,
+    walk: WalkOptions,
+    duplicates: bool,
+    json: bool,
+    now: SystemTime
+}
+
+// State shared by the walker threads: buffered stdout, whether any action
+// has failed, which becomes the exit status, and whether writing to stdout
+// has failed, which stops the walk.
+struct Context {
+    now: SystemTime,
+    out: Mutex<BufWriter<Stdout>>,
+    interactive: bool,
+    ok: AtomicBool,
+    closed: AtomicBool
+}
+
+// Options such as -maxdepth may appear anywhere in the expression; the
+// parser records them in `walk` and treats them as always true.
+struct Parser<'a> {
+    tokens: &'a [String],
+    pos: usize,
+    walk: WalkOptions
+}
+
+// A walked entry whose metadata is fetched on first use and then reused by
+// every predicate, so each entry is stat'd at most once. What the actions
+// print for it is kept in `output` until the expression has been evaluated.
+struct Entry {
+    path: PathBuf,
+    file_type: FileType,
+    followed: bool,
+    metadata: OnceCell<Option<Metadata>>,
+    head: OnceCell<Option<Vec<u8>>>,
+    prune: Cell<bool>,
+    output: RefCell<Vec<u8>>
 }
 
 pub fn get_args() -> MyResult<Config> {
+    let args = env::args().collect::<Vec<_>>();
+    let (args, expression) = args.split_at(expression_start(&args));
     let matches = App::new("findr")
         .version("0.1.0")
         .author("Hajime Nakamura <h.nakamura0903@gmail.com>")
         .about("Rust find")
+        .arg(Arg::with_name("follow_all").short("L").help("Follow all symbolic links").overrides_with_all(&["follow_roots", "follow_never"]))
+        .arg(Arg::with_name("follow_roots").short("H").help("Follow symbolic links given as paths only").overrides_with_all(&["follow_all", "follow_never"]))
+        .arg(Arg::with_name("follow_never").short("P").help("Never follow symbolic links (default)").overrides_with_all(&["follow_all", "follow_roots"]))
+        .arg(Arg::with_name("sort").value_name("SORTBY").long("sort").help("Sort output").possible_values(&["path", "none"]).default_value("none"))
+        .arg(Arg::with_name("threads").value_name("NUM").short("j").long("threads").help("Number of walker threads (default 1)"))
+        .arg(Arg::with_name("duplicates").long("duplicates").help("Print groups of regular files with identical contents"))
+        .arg(Arg::with_name("json").long("json").help("Print the --duplicates groups as JSON lines").requires("duplicates"))
         .arg(Arg::with_name("paths").value_name("PATH").help("Search paths").default_value(".").multiple(true))
-        .arg(Arg::with_name("names").value_name("NAME").short("n").long("name").help("Name").takes_value(true).multiple(true))
+        .arg(Arg::with_name("names").value_name("NAME").short("n").long("name").help("Name (shell glob)").takes_value(true).multiple(true))
         .arg(Arg::with_name("types").value_name("TYPE").short("t").long("type").help("Entry type").possible_values(&["f", "d", "l"]).takes_value(true).multiple(true))
-        .get_matches();
-
-    let names  = matches.values_of_lossy("names").map(|vals| {
-        vals.into_iter()
-            .map(|name| Regex::new(&name).map_err(|_| format!("Invalid --name \"{}\"", name)))
-            .collect::<Result<Vec<_>, _>>()
-    }).transpose()?.unwrap_or_default();
-    let entry_types = matches.values_of_lossy("types").map(|vals| {
-        vals.iter().map(|val| match val.as_str() {
-            "d" => Dir,
-            "f" => File,
-            "l" => Link,
-            _ => unreachable!("Invalid type"),
-        }).collect()
-    }).unwrap_or_default();
+        .arg(Arg::with_name("size").value_name("[+-]N[cwbkMG]").long("size").help("Size in units (default 512-byte blocks, rounded up)").multiple(true).number_of_values(1).allow_hyphen_values(true))
+        .arg(Arg::with_name("mtime").value_name("[+-]DAYS").long("mtime").help("Modified DAYS*24 hours ago").multiple(true).number_of_values(1).allow_hyphen_values(true))
+        .arg(Arg::with_name("mmin").value_name("[+-]MINUTES").long("mmin").help("Modified MINUTES ago").multiple(true).number_of_values(1).allow_hyphen_values(true))
+        .arg(Arg::with_name("atime").value_name("[+-]DAYS").long("atime").help("Accessed DAYS*24 hours ago").multiple(true).number_of_values(1).allow_hyphen_values(true))
+        .arg(Arg::with_name("amin").value_name("[+-]MINUTES").long("amin").help("Accessed MINUTES ago").multiple(true).number_of_values(1).allow_hyphen_values(true))
+        .arg(Arg::with_name("newer").value_name("FILE").long("newer").help("Modified more recently than FILE").multiple(true).number_of_values(1))
+        .arg(Arg::with_name("perm").value_name("[-/]MODE").long("perm").help("Octal permission bits: exactly, all of (-) or any of (/)").multiple(true).number_of_values(1).allow_hyphen_values(true))
+        .arg(Arg::with_name("user").value_name("USER").long("user").help("Owned by USER (name or uid)").multiple(true).number_of_values(1))
+        .arg(Arg::with_name("group").value_name("GROUP").long("group").help("Owned by GROUP (name or gid)").multiple(true).number_of_values(1))
+        .arg(Arg::with_name("empty").long("empty").help("Empty file or directory").takes_value(false))
+        .get_matches_from(args);
 
+    let mut tests = vec![];
+    for (name, flag) in [("names", "name"), ("types", "type")] {
+        let alternatives = matches.values_of_lossy(name).unwrap_or_default().iter()
+            .map(|val| parse_predicate(flag, val, &format!("--{}", flag)).map(Expr::Test))
+            .collect::<MyResult<Vec<_>>>()?;
+        if !alternatives.is_empty() {
+            tests.push(Expr::Or(alternatives));
+        }
+    }
+    for name in &VALUE_FLAGS {
+        for val in matches.values_of_lossy(name).unwrap_or_default() {
+            tests.push(Expr::Test(parse_predicate(name, &val, &format!("--{}", name))?));
+        }
+    }
+    if matches.is_present("empty") {
+        tests.push(Expr::Test(Predicate::Empty));
+    }
+    let follow = if matches.is_present("follow_all") {
+        Follow::Always
+    } else if matches.is_present("follow_roots") {
+        Follow::Roots
+    } else {
+        Follow::Never
+    };
+    let threads = match matches.value_of("threads") {
+        Some(val) => val.parse().ok().filter(|&n| n > 0).ok_or_else(|| format!("Invalid thread count \"{}\"", val))?,
+        None => 1,
+    };
+    let sort = matches.value_of("sort") == Some("path");
+    let mut walk = WalkOptions { min_depth: 0, max_depth: None, follow, same_file_system: false, contents_first: false, sort, threads };
+    if !expression.is_empty() {
+        let (expr, options) = parse_expression(expression, walk)?;
+        tests.push(expr);
+        walk = options;
+    }
+
+    let mut expr = Expr::And(tests);
+    expr.reorder();
+    if expr.any(&|expr| matches!(expr, Expr::Action(Action::Delete))) {
+        if expr.any(&|expr| matches!(expr, Expr::Test(Predicate::Prune))) {
+            return Err(From::from("-delete turns on -depth, so -prune would have no effect"));
+        }
+        if walk.follow == Follow::Always {
+            return Err(From::from("-delete cannot be combined with -L"));
+        }
+        walk.contents_first = true;
+    }
+    if walk.threads > 1 && (walk.contents_first || walk.follow == Follow::Roots) {
+        return Err(From::from("-depth, -delete and -H cannot be combined with --threads"));
+    }
+    let duplicates = matches.is_present("duplicates");
+    if duplicates {
+        if expr.any(&|expr| matches!(expr, Expr::Action(_))) {
+            return Err(From::from("--duplicates cannot be combined with actions"));
+        }
+        if walk.threads > 1 {
+            return Err(From::from("--duplicates cannot be combined with --threads"));
+        }
+    } else if !expr.any(&|expr| matches!(expr, Expr::Action(_))) {
+        expr = Expr::And(vec![expr, Expr::Action(Action::Print)]);
+    }
 
     Ok(Config {
         paths: matches.values_of_lossy("paths").unwrap(),
-        names,
-        entry_types,
+        expr,
+        walk,
+        duplicates,
+        json: matches.is_present("json"),
+        now: SystemTime::now(),
     })
 }
 
-pub fn run(config: Config) -> MyResult<()> {
-    let type_filter = |entry: &DirEntry| {
-        config.entry_types.is_empty() || config.entry_types.iter().any(|entry_type| {
-            match entry_type {
-                Link => entry.file_type().is_symlink(),
-                Dir => entry.file_type().is_dir(),
-                File => entry.file_type().is_file(),
+// Arguments from the first find-style token on (an operator, a primary
+// such as -name or any other single-dash word, as clap only has one-letter
+// short options) form the expression, so an unknown primary is reported by
+// the expression parser; the ones before it are parsed by clap.
+fn expression_start(args: &[String]) -> usize {
+    let mut i = 1;
+    while i < args.len() {
+        let arg = args[i].as_str();
+        if is_expression_start(arg) {
+            return i;
+        }
+        if VALUE_FLAGS.iter().any(|name| arg.strip_prefix("--") == Some(name)) {
+            i += 1;
+        }
+        i += 1;
+    }
+    args.len()
+}
+
+fn is_expression_start(arg: &str) -> bool {
+    matches!(arg, "(" | ")" | "!" | "-not" | "-a" | "-and" | "-o" | "-or" | "-empty" | "-true" | "-false")
+        || matches!(arg, "-binary" | "-text" | "-executable" | "-readable" | "-writable")
+        || matches!(arg, "-maxdepth" | "-mindepth" | "-prune" | "-xdev" | "-depth")
+        || matches!(arg, "-print" | "-print0" | "-printf" | "-delete" | "-exec")
+        || arg.strip_prefix('-').is_some_and(|name| VALUE_PRIMARIES.contains(&name))
+        || arg.strip_prefix('-').is_some_and(|name| !name.starts_with('-') && name.chars().count() > 1)
+}
+
+fn parse_expression(tokens: &[String], walk: WalkOptions) -> MyResult<(Expr, WalkOptions)> {
+    let mut parser = Parser { tokens, pos: 0, walk };
+    let expr = parser.parse_or()?;
+    match parser.peek() {
+        None => Ok((expr, parser.walk)),
+        Some(token) => Err(From::from(format!("Unexpected \"{}\"", token))),
+    }
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Option<&'a str> {
+        let token = self.peek();
+        self.pos += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> MyResult<Expr> {
+        let mut alternatives = vec![self.parse_and()?];
+        while let Some("-o" | "-or") = self.peek() {
+            self.pos += 1;
+            alternatives.push(self.parse_and()?);
+        }
+        Ok(if alternatives.len() == 1 { alternatives.remove(0) } else { Expr::Or(alternatives) })
+    }
+
+    fn parse_and(&mut self) -> MyResult<Expr> {
+        let mut terms = vec![self.parse_not()?];
+        loop {
+            match self.peek() {
+                None | Some("-o" | "-or" | ")") => break,
+                Some("-a" | "-and") => {
+                    self.pos += 1;
+                    terms.push(self.parse_not()?);
+                }
+                Some(_) => terms.push(self.parse_not()?),
+            }
+        }
+        Ok(if terms.len() == 1 { terms.remove(0) } else { Expr::And(terms) })
+    }
+
+    fn parse_not(&mut self) -> MyResult<Expr> {
+        match self.peek() {
+            Some("!" | "-not") => {
+                self.pos += 1;
+                Ok(Expr::Not(Box::new(self.parse_not()?)))
+            }
+            _ => self.parse_primary(),
+        }
+    }
+
+    fn parse_primary(&mut self) -> MyResult<Expr> {
+        match self.next() {
+            None => Err(From::from("Expected an expression at the end of the arguments")),
+            Some("(") => {
+                let expr = self.parse_or()?;
+                match self.next() {
+                    Some(")") => Ok(expr),
+                    _ => Err(From::from("Missing \")\"")),
+                }
+            }
+            Some(token @ (")" | "-a" | "-and" | "-o" | "-or")) => Err(From::from(format!("Expected an expression before \"{}\"", token))),
+            Some("-true") => Ok(Expr::And(vec![])),
+            Some("-false") => Ok(Expr::Or(vec![])),
+            Some("-empty") => Ok(Expr::Test(Predicate::Empty)),
+            Some("-prune") => Ok(Expr::Test(Predicate::Prune)),
+            Some("-binary") => Ok(Expr::Test(Predicate::Binary)),
+            Some("-text") => Ok(Expr::Test(Predicate::Text)),
+            Some("-readable") => Ok(Expr::Test(Predicate::Access(Access::Read))),
+            Some("-writable") => Ok(Expr::Test(Predicate::Access(Access::Write))),
+            Some("-executable") => Ok(Expr::Test(Predicate::Access(Access::Execute))),
+            Some("-print") => Ok(Expr::Action(Action::Print)),
+            Some("-print0") => Ok(Expr::Action(Action::Print0)),
+            Some("-delete") => Ok(Expr::Action(Action::Delete)),
+            Some("-printf") => {
+                let val = self.next().ok_or("Missing argument to \"-printf\"")?;
+                Ok(Expr::Action(Action::Printf(parse_format(val)?)))
+            }
+            Some("-exec") => self.parse_exec().map(Expr::Action),
+            Some(token @ ("-maxdepth" | "-mindepth")) => {
+                let val = self.next().ok_or_else(|| format!("Missing argument to \"{}\"", token))?;
+                let depth = val.parse().map_err(|_| format!("Invalid {} \"{}\"", token, val))?;
+                if token == "-maxdepth" {
+                    self.walk.max_depth = Some(depth);
+                } else {
+                    self.walk.min_depth = depth;
+                }
+                Ok(Expr::And(vec![]))
             }
-        })
+            Some("-xdev") => {
+                self.walk.same_file_system = true;
+                Ok(Expr::And(vec![]))
+            }
+            Some("-depth") => {
+                self.walk.contents_first = true;
+                Ok(Expr::And(vec![]))
+            }
+            Some(token) => match token.strip_prefix('-').filter(|name| VALUE_PRIMARIES.contains(name)) {
+                Some(name) => {
+                    let val = self.next().ok_or_else(|| format!("Missing argument to \"{}\"", token))?;
+                    Ok(Expr::Test(parse_predicate(name, val, token)?))
+                }
+                None => Err(From::from(format!("Unknown primary \"{}\"", token))),
+            },
+        }
+    }
+}
+
+impl Parser<'_> {
+    // The command runs up to ";", or up to "{} +" to pass many paths at once.
+    fn parse_exec(&mut self) -> MyResult<Action> {
+        let start = self.pos;
+        loop {
+            match self.next() {
+                None => return Err(From::from("Missing argument to \"-exec\"")),
+                Some(";") if self.pos - 1 > start => return Ok(Action::Exec(self.tokens[start..self.pos - 1].to_vec())),
+                Some("+") if self.pos - 2 > start && self.tokens[self.pos - 2] == "{}" => {
+                    let command = &self.tokens[start..self.pos - 2];
+                    if command.iter().any(|arg| arg.contains("{}")) {
+                        return Err(From::from("Only one instance of {} is supported with -exec ... +"));
+                    }
+                    return Ok(Action::ExecBatch(command.to_vec(), Mutex::new(vec![])));
+                }
+                Some(_) => {}
+            }
+        }
+    }
+}
+
+fn parse_format(val: &str) -> MyResult<Vec<Directive>> {
+    let mut directives = vec![];
+    let mut literal = String::new();
+    let mut chars = val.chars();
+    while let Some(c) = chars.next() {
+        let directive = match c {
+            '\\' => {
+                literal.push(match chars.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('0') => '\0',
+                    Some('\\') => '\\',
+                    other => return Err(From::from(format!("Invalid -printf escape \"\\{}\"", other.map(String::from).unwrap_or_default()))),
+                });
+                continue;
+            }
+            '%' => match chars.next() {
+                Some('%') => {
+                    literal.push('%');
+                    continue;
+                }
+                Some('p') => Directive::Path,
+                Some('s') => Directive::Size,
+                Some('m') => Directive::Mode,
+                Some('u') => Directive::User,
+                Some('T') if chars.next() == Some('@') => Directive::Modified,
+                _ => return Err(From::from(format!("Invalid -printf format \"{}\"", val))),
+            },
+            c => {
+                literal.push(c);
+                continue;
+            }
+        };
+        if !literal.is_empty() {
+            directives.push(Directive::Literal(std::mem::take(&mut literal)));
+        }
+        directives.push(directive);
+    }
+    if !literal.is_empty() {
+        directives.push(Directive::Literal(literal));
+    }
+    Ok(directives)
+}
+
+fn parse_predicate(name: &str, val: &str, flag: &str) -> MyResult<Predicate> {
+    let invalid = || format!("Invalid {} \"{}\"", flag, val);
+    Ok(match name {
+        "name" | "iname" => Predicate::Name(parse_glob(val, name.starts_with('i')).ok_or_else(invalid)?),
+        "path" | "ipath" => Predicate::Path(parse_glob(val, name.starts_with('i')).ok_or_else(invalid)?),
+        "regex" | "iregex" => Predicate::Regex(RegexBuilder::new(&format!("^(?:{})$", val)).case_insensitive(name == "iregex").build().map_err(|_| invalid())?),
+        "type" => Predicate::Type(match val {
+            "d" => Dir,
+            "f" => File,
+            "l" => Link,
+            _ => return Err(From::from(invalid())),
+        }),
+        "size" => parse_size(val).ok_or_else(invalid)?,
+        "mtime" | "mmin" | "atime" | "amin" => {
+            let comparison = parse_comparison(val).ok_or_else(invalid)?;
+            let unit = if name.ends_with("min") { 60 } else { 86400 };
+            if name.starts_with('m') { Predicate::Modified(comparison, unit) } else { Predicate::Accessed(comparison, unit) }
+        }
+        "newer" => Predicate::Newer(fs::metadata(val).and_then(|metadata| metadata.modified()).map_err(|e| format!("{}: {}", val, e))?),
+        "perm" => parse_perm(val).ok_or_else(invalid)?,
+        "user" => Predicate::User(parse_user(val).ok_or_else(invalid)?),
+        "group" => Predicate::Group(parse_group(val).ok_or_else(invalid)?),
+        "contains" => Predicate::Contains(regex::bytes::Regex::new(val).map_err(|_| invalid())?),
+        "mime" => Predicate::Mime(parse_glob(val, true).ok_or_else(invalid)?),
+        _ => unreachable!("Invalid primary"),
+    })
+}
+
+// As with fnmatch(3) in find, "*" and "?" also match "/" in -path patterns.
+fn parse_glob(val: &str, insensitive: bool) -> Option<GlobMatcher> {
+    GlobBuilder::new(val)
+        .case_insensitive(insensitive)
+        .literal_separator(false)
+        .backslash_escape(true)
+        .build()
+        .ok()
+        .map(|glob| glob.compile_matcher())
+}
+
+fn parse_comparison(val: &str) -> Option<Comparison> {
+    let (make, num): (fn(i64) -> Comparison, &str) = match val.as_bytes().first() {
+        Some(b'+') => (Comparison::More, &val[1..]),
+        Some(b'-') => (Comparison::Less, &val[1..]),
+        _ => (Comparison::Exactly, val),
     };
-    let name_filter = |entry: &DirEntry| {
-        config.names.is_empty() || config.names.iter().any(|re| re.is_match(&entry.file_name().to_string_lossy()))
+    if num.is_empty() || !num.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    num.parse().ok().map(make)
+}
+
+fn parse_size(val: &str) -> Option<Predicate> {
+    let (num, unit) = match val.char_indices().last()? {
+        (i, 'c') => (&val[..i], 1),
+        (i, 'w') => (&val[..i], 2),
+        (i, 'b') => (&val[..i], 512),
+        (i, 'k') => (&val[..i], 1024),
+        (i, 'M') => (&val[..i], 1024 * 1024),
+        (i, 'G') => (&val[..i], 1024 * 1024 * 1024),
+        _ => (val, 512),
     };
-    
-
-    for path in config.paths {
-        let entries = WalkDir::new(path)
-            .into_iter()
-            .filter_map(|e| match e {
-                Err(e) => {
-                    eprintln!("{}", e);
-                    None
-                },
-                Ok(entry) => Some(entry)
-            }).filter(type_filter).filter(name_filter).map(|entry| entry.path().display().to_string()).collect::<Vec<_>>();
-        println!("{}", entries.join("\n"));
-    }
-    Ok(())
-}
\ No newline at end of file
+    parse_comparison(num).map(|comparison| Predicate::Size(comparison, unit))
+}
+
+fn parse_perm(val: &str) -> Option<Predicate> {
+    let (mode, bits) = match val.as_bytes().first() {
+        Some(b'-') => (PermMatch::All, &val[1..]),
+        Some(b'/') => (PermMatch::Any, &val[1..]),
+        _ => (PermMatch::Exact, val),
+    };
+    u32::from_str_radix(bits, 8).ok().filter(|bits| *bits <= 0o7777).map(|bits| Predicate::Perm(mode, bits))
+}
+
+fn parse_user(val: &str) -> Option<u32> {
+    val.parse().ok().or_else(|| users::get_user_by_name(val).map(|user| user.uid()))
+}
+
+fn parse_group(val: &str) -> Option<u32> {
+    val.parse().ok().or_else(|| users::get_group_by_name(val).map(|group| group.gid()))
+}
+
+impl Comparison {
+    fn matches(self, n: i64) -> bool {
+        match self {
+            Comparison::Less(limit) => n < limit,
+            Comparison::Exactly(limit) => n == limit,
+            Comparison::More(limit) => n > limit,
+        }
+    }
+}
+
+impl Entry {
+    // `path_is_symlink` comes from the walker: a symlink that was followed
+    // has the file type of its target and is stat'd through the link.
+    fn new(path: &Path, file_type: FileType, path_is_symlink: bool) -> Entry {
+        Entry {
+            path: path.to_path_buf(),
+            file_type,
+            followed: path_is_symlink && !file_type.is_symlink(),
+            metadata: OnceCell::new(),
+            head: OnceCell::new(),
+            prune: Cell::new(false),
+            output: RefCell::new(vec![]),
+        }
+    }
+
+    fn file_name(&self) -> &OsStr {
+        self.path.file_name().unwrap_or(self.path.as_os_str())
+    }
+
+    fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.get_or_init(|| if self.followed { fs::metadata(&self.path) } else { fs::symlink_metadata(&self.path) }.ok()).as_ref()
+    }
+
+    // The first HEAD_BYTES of a regular file, read once.
+    fn head(&self) -> Option<&[u8]> {
+        self.head.get_or_init(|| {
+            if !self.metadata()?.is_file() {
+                return None;
+            }
+            let mut head = Vec::with_capacity(HEAD_BYTES);
+            File::open(&self.path).ok()?.take(HEAD_BYTES as u64).read_to_end(&mut head).ok()?;
+            Some(head)
+        }).as_deref()
+    }
+
+    fn write(&self, bytes: &[u8]) {
+        self.output.borrow_mut().extend_from_slice(bytes);
+    }
+}
+
+impl Expr {
+    fn matches(&self, entry: &Entry, ctx: &Context) -> bool {
+        match self {
+            Expr::Test(predicate) => predicate.matches(entry, ctx.now),
+            Expr::Action(action) => action.run(entry, ctx),
+            Expr::Not(expr) => !expr.matches(entry, ctx),
+            Expr::And(exprs) => exprs.iter().all(|expr| expr.matches(entry, ctx)),
+            Expr::Or(exprs) => exprs.iter().any(|expr| expr.matches(entry, ctx)),
+        }
+    }
+
+    // None for expressions with side effects, which have to keep their place.
+    fn cost(&self) -> Option<u8> {
+        match self {
+            Expr::Test(Predicate::Prune) | Expr::Action(_) => None,
+            Expr::Test(predicate) => Some(predicate.cost()),
+            Expr::Not(expr) => expr.cost(),
+            Expr::And(exprs) | Expr::Or(exprs) => exprs.iter().try_fold(0, |max, expr| expr.cost().map(|cost| max.max(cost))),
+        }
+    }
+
+    // Moves the expensive operands of -a and -o after the cheaper ones, so
+    // that short-circuiting skips reading files where it can. Operands with
+    // side effects stay where they are and nothing moves across them.
+    fn reorder(&mut self) {
+        match self {
+            Expr::Not(expr) => expr.reorder(),
+            Expr::And(exprs) | Expr::Or(exprs) => {
+                exprs.iter_mut().for_each(Expr::reorder);
+                for run in exprs.split_mut(|expr| expr.cost().is_none()) {
+                    run.sort_by_key(Expr::cost);
+                }
+            }
+            Expr::Test(_) | Expr::Action(_) => {}
+        }
+    }
+
+    fn any(&self, pred: &dyn Fn(&Expr) -> bool) -> bool {
+        pred(self) || match self {
+            Expr::Not(expr) => expr.any(pred),
+            Expr::And(exprs) | Expr::Or(exprs) => exprs.iter().any(|expr| expr.any(pred)),
+            Expr::Test(_) | Expr::Action(_) => false,
+        }
+    }
+
+    // Runs the `-exec ... {} +` commands still holding paths.
+    fn finish(&self, ctx: &Context) {
+        match self {
+            Expr::Action(Action::ExecBatch(command, pending)) => exec_batch(command, &mut pending.lock().unwrap(), ctx),
+            Expr::Not(expr) => expr.finish(ctx),
+            Expr::And(exprs) | Expr::Or(exprs) => exprs.iter().for_each(|expr| expr.finish(ctx)),
+            Expr::Test(_) | Expr::Action(_) => {}
+        }
+    }
+}
+
+impl Context {
+    fn new(now: SystemTime) -> Context {
+        let interactive = io::stdout().is_terminal();
+        Context { now, out: Mutex::new(BufWriter::new(io::stdout())), interactive, ok: AtomicBool::new(true), closed: AtomicBool::new(false) }
+    }
+
+    // On a terminal each entry is flushed as it is written so matches show
+    // up during a long walk; pipes keep the block buffering.
+    fn write(&self, bytes: &[u8]) {
+        if bytes.is_empty() || self.closed() {
+            return;
+        }
+        let mut out = self.out.lock().unwrap();
+        let result = out.write_all(bytes).and_then(|_| if self.interactive { out.flush() } else { Ok(()) });
+        if let Err(e) = result {
+            self.close(e);
+        }
+    }
+
+    fn flush(&self) {
+        if self.closed() {
+            return;
+        }
+        if let Err(e) = self.out.lock().unwrap().flush() {
+            self.close(e);
+        }
+    }
+
+    // A failed write (such as a closed pipe) is reported once and ends the
+    // walk, as nothing more can be printed.
+    fn close(&self, e: io::Error) {
+        if !self.closed.swap(true, Ordering::Relaxed) {
+            self.fail(e);
+        }
+    }
+
+    fn closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    fn fail(&self, e: impl std::fmt::Display) {
+        eprintln!("{}", e);
+        self.ok.store(false, Ordering::Relaxed);
+    }
+}
+
+impl Action {
+    fn run(&self, entry: &Entry, ctx: &Context) -> bool {
+        let path = entry.path.as_path();
+        match self {
+            Action::Print => {
+                entry.write(path.as_os_str().as_encoded_bytes());
+                entry.write(b"\n");
+            }
+            Action::Print0 => {
+                entry.write(path.as_os_str().as_encoded_bytes());
+                entry.write(b"\0");
+            }
+            Action::Printf(directives) => {
+                for directive in directives {
+                    entry.write(format_directive(directive, entry).as_bytes());
+                }
+            }
+            Action::Delete => {
+                // Like find, never try to remove the starting directory ".".
+                if path == Path::new(".") {
+                    return true;
+                }
+                let result = if entry.file_type.is_dir() { fs::remove_dir(path) } else { fs::remove_file(path) };
+                if let Err(e) = result {
+                    ctx.fail(format!("{}: {}", path.display(), e));
+                    return false;
+                }
+            }
+            Action::Exec(command) => {
+                let path = path.display().to_string();
+                let args = command.iter().map(|arg| arg.replace("{}", &path)).collect::<Vec<_>>();
+                // Whatever was printed for the entry so far has to come out
+                // before the command's own output.
+                ctx.write(&entry.output.take());
+                return execute(&args, ctx).unwrap_or(false);
+            }
+            Action::ExecBatch(command, pending) => {
+                let mut pending = pending.lock().unwrap();
+                pending.push(path.display().to_string());
+                if pending.iter().map(|path| path.len() + 1).sum::<usize>() >= EXEC_BATCH_BYTES {
+                    exec_batch(command, &mut pending, ctx);
+                }
+            }
+        }
+        true
+    }
+}
+
+// Returns whether the command succeeded, or None when it could not be run.
+fn execute(args: &[String], ctx: &Context) -> Option<bool> {
+    ctx.flush();
+    match Command::new(&args[0]).args(&args[1..]).status() {
+        Ok(status) => Some(status.success()),
+        Err(e) => {
+            ctx.fail(format!("{}: {}", args[0], e));
+            None
+        }
+    }
+}
+
+// As with find, a failing batch does not change the expression's result but
+// does make the exit status non-zero.
+fn exec_batch(command: &[String], pending: &mut Vec<String>, ctx: &Context) {
+    if pending.is_empty() {
+        return;
+    }
+    let args = command.iter().chain(pending.iter()).cloned().collect::<Vec<_>>();
+    pending.clear();
+    if execute(&args, ctx) == Some(false) {
+        ctx.ok.store(false, Ordering::Relaxed);
+    }
+}
+
+fn format_directive(directive: &Directive, entry: &Entry) -> String {
+    let metadata = entry.metadata();
+    match directive {
+        Directive::Literal(text) => text.clone(),
+        Directive::Path => entry.path.display().to_string(),
+        Directive::Size => metadata.map(|metadata| metadata.len().to_string()).unwrap_or_default(),
+        Directive::Modified => metadata.and_then(|metadata| metadata.modified().ok())
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|elapsed| format!("{}.{:09}0", elapsed.as_secs(), elapsed.subsec_nanos()))
+            .unwrap_or_default(),
+        Directive::Mode => metadata.and_then(mode).map(|mode| format!("{:o}", mode)).unwrap_or_default(),
+        Directive::User => metadata.and_then(owner).map(|(uid, _)| {
+            users::get_user_by_uid(uid).map(|user| user.name().to_string_lossy().into_owned()).unwrap_or_else(|| uid.to_string())
+        }).unwrap_or_default(),
+    }
+}
+
+impl Predicate {
+    fn cost(&self) -> u8 {
+        match self {
+            Predicate::Name(_) | Predicate::Path(_) | Predicate::Regex(_) | Predicate::Type(_) | Predicate::Prune => 0,
+            Predicate::Contains(_) | Predicate::Binary | Predicate::Text | Predicate::Mime(_) => 2,
+            _ => 1,
+        }
+    }
+
+    fn matches(&self, entry: &Entry, now: SystemTime) -> bool {
+        match self {
+            Predicate::Name(glob) => return glob.is_match(entry.file_name()),
+            Predicate::Path(glob) => return glob.is_match(&entry.path),
+            Predicate::Regex(re) => return re.is_match(&entry.path.to_string_lossy()),
+            Predicate::Type(entry_type) => return match entry_type {
+                Link => entry.file_type.is_symlink(),
+                Dir => entry.file_type.is_dir(),
+                File => entry.file_type.is_file(),
+            },
+            Predicate::Prune => {
+                entry.prune.set(true);
+                return true;
+            }
+            _ => {}
+        }
+        let metadata = match entry.metadata() {
+            Some(metadata) => metadata,
+            None => return false,
+        };
+        match self {
+            Predicate::Size(comparison, unit) => comparison.matches(metadata.len().div_ceil(*unit) as i64),
+            Predicate::Modified(comparison, unit) => metadata.modified().is_ok_and(|time| comparison.matches(age(now, time, *unit))),
+            Predicate::Accessed(comparison, unit) => metadata.accessed().is_ok_and(|time| comparison.matches(age(now, time, *unit))),
+            Predicate::Newer(reference) => metadata.modified().is_ok_and(|time| time > *reference),
+            Predicate::Perm(mode, bits) => perm_matches(metadata, *mode, *bits),
+            Predicate::User(uid) => owner(metadata).is_some_and(|(owner, _)| owner == *uid),
+            Predicate::Group(gid) => owner(metadata).is_some_and(|(_, group)| group == *gid),
+            Predicate::Name(_) | Predicate::Path(_) | Predicate::Regex(_) | Predicate::Type(_) | Predicate::Prune => unreachable!(),
+            Predicate::Empty => {
+                if metadata.is_dir() {
+                    fs::read_dir(&entry.path).is_ok_and(|mut entries| entries.next().is_none())
+                } else {
+                    metadata.is_file() && metadata.len() == 0
+                }
+            }
+            Predicate::Access(access) => accessible(&entry.path, metadata, *access),
+            Predicate::Contains(re) => metadata.is_file() && File::open(&entry.path)
+                .is_ok_and(|file| BufReader::new(file).split(b'\n').map_while(Result::ok).any(|line| re.is_match(&line))),
+            Predicate::Binary => entry.head().is_some_and(mime::is_binary),
+            Predicate::Text => entry.head().is_some_and(|head| !mime::is_binary(head)),
+            Predicate::Mime(glob) => entry.head().is_some_and(|head| glob.is_match(mime::detect(head))),
+        }
+    }
+}
+
+// Whole units elapsed since `time`, rounded down like find's -mtime.
+fn age(now: SystemTime, time: SystemTime, unit: u64) -> i64 {
+    match now.duration_since(time) {
+        Ok(elapsed) => (elapsed.as_secs() / unit) as i64,
+        Err(e) => -((e.duration().as_secs() / unit) as i64) - 1,
+    }
+}
+
+fn perm_matches(metadata: &Metadata, matching: PermMatch, bits: u32) -> bool {
+    let Some(perm) = mode(metadata) else {
+        return false;
+    };
+    match matching {
+        PermMatch::Exact => perm == bits,
+        PermMatch::All => perm & bits == bits,
+        PermMatch::Any => bits == 0 || perm & bits != 0,
+    }
+}
+
+#[cfg(unix)]
+fn mode(metadata: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn mode(_metadata: &Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn accessible(path: &Path, _metadata: &Metadata, access: Access) -> bool {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+    let mode = match access {
+        Access::Read => libc::R_OK,
+        Access::Write => libc::W_OK,
+        Access::Execute => libc::X_OK,
+    };
+    CString::new(path.as_os_str().as_bytes()).is_ok_and(|path| unsafe { libc::access(path.as_ptr(), mode) } == 0)
+}
+
+#[cfg(not(unix))]
+fn accessible(_path: &Path, metadata: &Metadata, access: Access) -> bool {
+    match access {
+        Access::Read => true,
+        Access::Write => !metadata.permissions().readonly(),
+        Access::Execute => false,
+    }
+}
+
+#[cfg(unix)]
+fn owner(metadata: &Metadata) -> Option<(u32, u32)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.uid(), metadata.gid()))
+}
+
+#[cfg(not(unix))]
+fn owner(_metadata: &Metadata) -> Option<(u32, u32)> {
+    None
+}
+
+#[cfg(unix)]
+fn inode(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+impl WalkOptions {
+    fn walker(&self, path: &str) -> WalkDir {
+        let walker = WalkDir::new(path)
+            .min_depth(self.min_depth)
+            .follow_links(self.follow == Follow::Always)
+            .follow_root_links(self.follow != Follow::Never)
+            .same_file_system(self.same_file_system)
+            .contents_first(self.contents_first);
+        let walker = if self.sort { walker.sort_by_file_name() } else { walker };
+        match self.max_depth {
+            Some(depth) => walker.max_depth(depth),
+            None => walker,
+        }
+    }
+
+    fn parallel_walker(&self, path: &str) -> WalkBuilder {
+        let mut builder = WalkBuilder::new(path);
+        builder
+            .standard_filters(false)
+            .follow_links(self.follow == Follow::Always)
+            .same_file_system(self.same_file_system)
+            .max_depth(self.max_depth)
+            .threads(self.threads);
+        builder
+    }
+}
+
+pub fn run(config: Config) -> MyResult<bool> {
+    let ctx = Context::new(config.now);
+    if config.duplicates {
+        print_duplicates(&config, &ctx);
+        ctx.flush();
+        return Ok(ctx.ok.load(Ordering::Relaxed));
+    }
+    for path in config.paths.iter().take_while(|_| !ctx.closed()) {
+        if config.walk.threads > 1 {
+            walk_parallel(&config, path, &ctx);
+        } else {
+            walk(&config, path, &ctx, |_| {});
+        }
+    }
+    if !ctx.closed() {
+        config.expr.finish(&ctx);
+    }
+    ctx.flush();
+    Ok(ctx.ok.load(Ordering::Relaxed))
+}
+
+// `visit` is called with each entry the expression matched.
+fn walk(config: &Config, path: &str, ctx: &Context, mut visit: impl FnMut(&Entry)) {
+    let mut walker = config.walk.walker(path).into_iter();
+    while let Some(result) = walker.next() {
+        let entry = match result {
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+            Ok(entry) => Entry::new(entry.path(), entry.file_type(), entry.path_is_symlink()),
+        };
+        if config.expr.matches(&entry, ctx) {
+            visit(&entry);
+        }
+        ctx.write(&entry.output.take());
+        if ctx.closed() {
+            break;
+        }
+        // With -depth a directory comes after its contents, so there is
+        // nothing left to prune (as in find).
+        if entry.prune.get() && entry.file_type.is_dir() && !config.walk.contents_first {
+            walker.skip_current_dir();
+        }
+    }
+}
+
+// The walker threads evaluate the expression and send what each entry
+// printed to this thread, which writes it straight away or, with --sort,
+// in path order once the walk is done.
+fn walk_parallel(config: &Config, path: &str, ctx: &Context) {
+    let (tx, rx) = mpsc::channel::<(PathBuf, Vec<u8>)>();
+    thread::scope(|scope| {
+        scope.spawn(move || {
+            config.walk.parallel_walker(path).build_parallel().run(|| {
+                let tx = tx.clone();
+                Box::new(move |result| {
+                    if ctx.closed() {
+                        return WalkState::Quit;
+                    }
+                    let entry = match result {
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            return WalkState::Continue;
+                        }
+                        Ok(entry) => match entry.file_type() {
+                            Some(file_type) if entry.depth() >= config.walk.min_depth => Entry::new(entry.path(), file_type, entry.path_is_symlink()),
+                            _ => return WalkState::Continue,
+                        },
+                    };
+                    config.expr.matches(&entry, ctx);
+                    let output = entry.output.take();
+                    if !output.is_empty() && tx.send((entry.path.clone(), output)).is_err() {
+                        return WalkState::Quit;
+                    }
+                    if entry.prune.get() && entry.file_type.is_dir() {
+                        WalkState::Skip
+                    } else {
+                        WalkState::Continue
+                    }
+                })
+            });
+        });
+
+        if config.walk.sort {
+            rx.into_iter().collect::<BTreeMap<_, _>>().values().for_each(|output| ctx.write(output));
+        } else {
+            for (_, output) in rx {
+                ctx.write(&output);
+                if ctx.closed() {
+                    break;
+                }
+            }
+        }
+    });
+}
+
+// The expression selects the candidates; groups are separated by an empty
+// line, or written as JSON lines followed by a summary.
+fn print_duplicates(config: &Config, ctx: &Context) {
+    let mut candidates = vec![];
+    for path in &config.paths {
+        walk(config, path, ctx, |entry| {
+            if let Some(metadata) = entry.metadata().filter(|metadata| metadata.is_file()) {
+                candidates.push(duplicates::Candidate { path: entry.path.clone(), size: metadata.len(), inode: inode(metadata) });
+            }
+        });
+    }
+
+    let groups = duplicates::find(candidates, ctx);
+    for (i, group) in groups.iter().enumerate().take_while(|_| !ctx.closed()) {
+        if config.json {
+            let paths = group.paths.iter().map(|path| path.display().to_string()).collect::<Vec<_>>();
+            let data = json!({ "size": group.size, "hash": group.hash, "files": group.files, "paths": paths });
+            ctx.write(format!("{}\n", json!({ "type": "duplicates", "data": data })).as_bytes());
+            continue;
+        }
+        if i > 0 {
+            ctx.write(b"\n");
+        }
+        for path in &group.paths {
+            ctx.write(path.as_os_str().as_encoded_bytes());
+            ctx.write(b"\n");
+        }
+    }
+    if config.json {
+        let files = groups.iter().map(|group| group.files).sum::<usize>();
+        let reclaimable = groups.iter().map(|group| group.size * (group.files as u64 - 1)).sum::<u64>();
+        let data = json!({ "groups": groups.len(), "files": files, "reclaimable_bytes": reclaimable });
+        ctx.write(format!("{}\n", json!({ "type": "summary", "data": data })).as_bytes());
+    }
+}
@@ -0,0 +1,42 @@
+// A magic number as (offset, bytes) pairs that must all match.
+type Signature = &'static [(usize, &'static [u8])];
+
+const MAGIC: &[(Signature, &str)] = &[
+    (&[(0, b"\x89PNG\r\n\x1a\n")], "image/png"),
+    (&[(0, b"\xff\xd8\xff")], "image/jpeg"),
+    (&[(0, b"GIF87a")], "image/gif"),
+    (&[(0, b"GIF89a")], "image/gif"),
+    (&[(0, b"RIFF"), (8, b"WEBP")], "image/webp"),
+    (&[(0, b"II*\0")], "image/tiff"),
+    (&[(0, b"MM\0*")], "image/tiff"),
+    (&[(0, b"%PDF-")], "application/pdf"),
+    (&[(0, b"PK\x03\x04")], "application/zip"),
+    (&[(0, b"\x1f\x8b")], "application/gzip"),
+    (&[(0, b"BZh")], "application/x-bzip2"),
+    (&[(0, b"\xfd7zXZ\0")], "application/x-xz"),
+    (&[(0, b"\x28\xb5\x2f\xfd")], "application/zstd"),
+    (&[(257, b"ustar")], "application/x-tar"),
+    (&[(0, b"\x7fELF")], "application/x-executable"),
+    (&[(0, b"ID3")], "audio/mpeg"),
+    (&[(0, b"OggS")], "audio/ogg"),
+    (&[(0, b"fLaC")], "audio/flac"),
+    (&[(0, b"RIFF"), (8, b"WAVE")], "audio/wav"),
+    (&[(4, b"ftyp")], "video/mp4"),
+];
+
+pub fn is_binary(head: &[u8]) -> bool {
+    head.contains(&0)
+}
+
+// Files without a known signature are text/plain unless they look binary.
+pub fn detect(head: &[u8]) -> &'static str {
+    let found = MAGIC.iter().find(|(checks, _)| {
+        checks.iter().all(|(offset, magic)| head.get(*offset..offset + magic.len()) == Some(*magic))
+    });
+    match found {
+        Some((_, mime)) => mime,
+        None if head.is_empty() => "application/x-empty",
+        None if is_binary(head) => "application/octet-stream",
+        None => "text/plain",
+    }
+}
@@ -1,7 +1,10 @@
+#![allow(clippy::needless_borrows_for_generic_args)]
+
 use assert_cmd::Command;
 use predicates::prelude::*;
 use rand::{distributions::Alphanumeric, Rng};
-use std::{borrow::Cow, fs, path::Path};
+use std::{borrow::Cow, fs, io, path::Path, time::{Duration, SystemTime}};
+use tempfile::TempDir;
 
 type TestResult = Result<(), Box<dyn std::error::Error>>;
 
@@ -39,10 +42,10 @@ fn skips_bad_dir() -> TestResult {
 #[test]
 fn dies_bad_name() -> TestResult {
     Command::cargo_bin(PRG)?
-        .args(&["--name", "*.csv"])
+        .args(&["--name", "[a-"])
         .assert()
         .failure()
-        .stderr(predicate::str::contains("Invalid --name \"*.csv\""));
+        .stderr(predicate::str::contains("Invalid --name \"[a-\""));
     Ok(())
 }
 
@@ -60,14 +63,14 @@ fn dies_bad_type() -> TestResult {
 
 // --------------------------------------------------
 #[cfg(windows)]
-fn format_file_name(expected_file: &str) -> Cow<str> {
+fn format_file_name(expected_file: &str) -> Cow<'_, str> {
     // Equivalent to: Cow::Owned(format!("{}.windows", expected_file))
     format!("{}.windows", expected_file).into()
 }
 
 // --------------------------------------------------
 #[cfg(not(windows))]
-fn format_file_name(expected_file: &str) -> Cow<str> {
+fn format_file_name(expected_file: &str) -> Cow<'_, str> {
     // Equivalent to: Cow::Borrowed(expected_file)
     expected_file.into()
 }
@@ -228,7 +231,7 @@ fn type_f_l() -> TestResult {
 #[test]
 fn name_csv() -> TestResult {
     run(
-        &["tests/inputs", "-n", ".*[.]csv"],
+        &["tests/inputs", "-n", "*.csv"],
         "tests/expected/name_csv.txt",
     )
 }
@@ -237,7 +240,7 @@ fn name_csv() -> TestResult {
 #[test]
 fn name_csv_mp3() -> TestResult {
     run(
-        &["tests/inputs", "-n", ".*[.]csv", "-n", ".*[.]mp3"],
+        &["tests/inputs", "-n", "*.csv", "-n", "*.mp3"],
         "tests/expected/name_csv_mp3.txt",
     )
 }
@@ -246,7 +249,7 @@ fn name_csv_mp3() -> TestResult {
 #[test]
 fn name_txt_path_a_d() -> TestResult {
     run(
-        &["tests/inputs/a", "tests/inputs/d", "--name", ".*.txt"],
+        &["tests/inputs/a", "tests/inputs/d", "--name", "*.txt"],
         "tests/expected/name_txt_path_a_d.txt",
     )
 }
@@ -254,14 +257,14 @@ fn name_txt_path_a_d() -> TestResult {
 // --------------------------------------------------
 #[test]
 fn name_a() -> TestResult {
-    run(&["tests/inputs", "-n", "a"], "tests/expected/name_a.txt")
+    run(&["tests/inputs", "-n", "a*"], "tests/expected/name_a.txt")
 }
 
 // --------------------------------------------------
 #[test]
 fn type_f_name_a() -> TestResult {
     run(
-        &["tests/inputs", "-t", "f", "-n", "a"],
+        &["tests/inputs", "-t", "f", "-n", "a*"],
         "tests/expected/type_f_name_a.txt",
     )
 }
@@ -270,7 +273,7 @@ fn type_f_name_a() -> TestResult {
 #[test]
 fn type_d_name_a() -> TestResult {
     run(
-        &["tests/inputs", "--type", "d", "--name", "a"],
+        &["tests/inputs", "--type", "d", "--name", "a*"],
         "tests/expected/type_d_name_a.txt",
     )
 }
@@ -316,3 +319,560 @@ fn unreadable_dir() -> TestResult {
     assert!(stderr.contains("cant-touch-this: Permission denied"));
     Ok(())
 }
+
+// --------------------------------------------------
+fn make_tree() -> Result<TempDir, Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let day = Duration::from_secs(24 * 60 * 60);
+    let now = SystemTime::now();
+    fs::create_dir(dir.path().join("emptydir"))?;
+    fs::create_dir(dir.path().join("sub"))?;
+    for (name, size, age) in [("empty.txt", 0, 0), ("small.txt", 100, 2), ("big.bin", 3000, 10), ("sub/new.txt", 10, 0)] {
+        let path = dir.path().join(name);
+        fs::write(&path, "x".repeat(size))?;
+        fs::File::options().write(true).open(&path)?.set_modified(now - day * age)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = if name == "big.bin" { 0o755 } else { 0o644 };
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+        }
+    }
+    Ok(dir)
+}
+
+// --------------------------------------------------
+fn run_tree(args: &[&str], expected: &[&str]) -> TestResult {
+    run_tree_with(make_tree, args, expected)
+}
+
+// --------------------------------------------------
+fn run_tree_with(
+    make: fn() -> Result<TempDir, Box<dyn std::error::Error>>,
+    args: &[&str],
+    expected: &[&str],
+) -> TestResult {
+    let dir = make()?;
+    let cmd = Command::cargo_bin(PRG)?
+        .current_dir(dir.path())
+        .arg(".")
+        .args(args)
+        .assert()
+        .success();
+    let stdout = String::from_utf8(cmd.get_output().stdout.clone())?;
+    let mut lines: Vec<&str> =
+        stdout.split('\n').filter(|s| !s.is_empty()).collect();
+    lines.sort();
+    let mut expected = expected.to_vec();
+    expected.sort();
+    assert_eq!(lines, expected);
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn size() -> TestResult {
+    run_tree(&["-t", "f", "--size", "+2k"], &["./big.bin"])?;
+    run_tree(&["--size", "100c"], &["./small.txt"])?;
+    run_tree(&["--size", "-1k"], &["./empty.txt"])?;
+    run_tree(&["-t", "f", "--size", "1"], &["./small.txt", "./sub/new.txt"])
+}
+
+// --------------------------------------------------
+#[test]
+fn mtime() -> TestResult {
+    run_tree(&["--mtime", "+5"], &["./big.bin"])?;
+    run_tree(&["-t", "f", "--mtime", "-1"], &["./empty.txt", "./sub/new.txt"])?;
+    run_tree(&["-t", "f", "--mtime", "+1", "--mtime", "-5"], &["./small.txt"])?;
+    run_tree(&["-t", "f", "--mmin", "+60"], &["./big.bin", "./small.txt"])
+}
+
+// --------------------------------------------------
+#[test]
+fn newer() -> TestResult {
+    run_tree(&["-t", "f", "--newer", "small.txt"], &["./empty.txt", "./sub/new.txt"])
+}
+
+// --------------------------------------------------
+#[test]
+fn empty() -> TestResult {
+    run_tree(&["--empty"], &["./empty.txt", "./emptydir"])?;
+    run_tree(&["--empty", "-t", "d"], &["./emptydir"])
+}
+
+// --------------------------------------------------
+#[test]
+#[cfg(unix)]
+fn perm() -> TestResult {
+    run_tree(&["-t", "f", "--perm", "755"], &["./big.bin"])?;
+    run_tree(&["-t", "f", "--perm", "/111"], &["./big.bin"])?;
+    run_tree(&["-t", "f", "--perm", "-644"], &["./big.bin", "./empty.txt", "./small.txt", "./sub/new.txt"])
+}
+
+// --------------------------------------------------
+#[test]
+#[cfg(unix)]
+fn user_group() -> TestResult {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = fs::metadata(make_tree()?.path().join("big.bin"))?;
+    let (uid, gid) = (metadata.uid().to_string(), metadata.gid().to_string());
+    let all = ["./big.bin", "./empty.txt", "./small.txt", "./sub/new.txt"];
+    run_tree(&["-t", "f", "--user", &uid], &all)?;
+    run_tree(&["-t", "f", "--group", &gid], &all)?;
+    run_tree(&["-t", "f", "--user", "4000000000"], &[])
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_predicates() -> TestResult {
+    for (flag, val) in [("--size", "+x"), ("--mtime", "1.5"), ("--perm", "999"), ("--user", "no-such-user-findr")] {
+        Command::cargo_bin(PRG)?
+            .args([flag, val])
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(format!("Invalid {} \"{}\"", flag, val)));
+    }
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn expr_group() -> TestResult {
+    run(
+        &["tests/inputs", "(", "-name", "*.csv", "-o", "-name", "*.mp3", ")", "-type", "f"],
+        "tests/expected/expr_group.txt",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn expr_not() -> TestResult {
+    run(
+        &["tests/inputs", "-type", "f", "!", "-name", "*.txt"],
+        "tests/expected/expr_not.txt",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn expr_not_and() -> TestResult {
+    run(
+        &["tests/inputs", "-not", "-type", "d", "-a", "-name", "b*"],
+        "tests/expected/expr_not_and.txt",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn expr_with_legacy_flags() -> TestResult {
+    run_tree(&["-t", "f", "-size", "-1k", "-o", "-mtime", "+5"], &["./big.bin", "./empty.txt"])?;
+    run_tree(&["--empty", "-not", "-type", "d"], &["./empty.txt"])?;
+    run_tree(&["-true"], &[".", "./big.bin", "./empty.txt", "./emptydir", "./small.txt", "./sub", "./sub/new.txt"])?;
+    run_tree(&["-false"], &[])
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_expression() -> TestResult {
+    for (args, expected) in [
+        (&["(", "-name", "a"][..], "Missing \")\""),
+        (&["-o", "-name", "a"], "Expected an expression before \"-o\""),
+        (&["-name"], "Missing argument to \"-name\""),
+        (&["-name", "a", ")"], "Unexpected \")\""),
+        (&["-name", "a", "-o"], "Expected an expression at the end"),
+        (&["-type", "x"], "Invalid -type \"x\""),
+        (&["-name", "[a-"], "Invalid -name \"[a-\""),
+        (&["-regex", "*"], "Invalid -regex \"*\""),
+        (&["-maxdepth", "x"], "Invalid -maxdepth \"x\""),
+        (&["-mindepth"], "Missing argument to \"-mindepth\""),
+        (&["-printf", "%x"], "Invalid -printf format \"%x\""),
+        (&["-exec", "echo", "{}"], "Missing argument to \"-exec\""),
+        (&["-exec", "echo", "{}", "{}", "+"], "Only one instance of {} is supported"),
+        (&["-prune", "-delete"], "-prune would have no effect"),
+        (&["-contains", "("], "Invalid -contains \"(\""),
+        (&["-mime", "[image"], "Invalid -mime \"[image\""),
+        (&["a", "-foo"], "Unknown primary \"-foo\""),
+        (&["a", "-name", "a", "-foo"], "Unknown primary \"-foo\""),
+        (&["a", "-nouser"], "Unknown primary \"-nouser\""),
+        (&["a", "-hidden"], "Unknown primary \"-hidden\""),
+    ] {
+        Command::cargo_bin(PRG)?
+            .args(args)
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(expected));
+    }
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn depth_limits() -> TestResult {
+    run_tree(&["-maxdepth", "1"], &[".", "./big.bin", "./empty.txt", "./emptydir", "./small.txt", "./sub"])?;
+    run_tree(&["-maxdepth", "0"], &["."])?;
+    run_tree(&["-mindepth", "2"], &["./sub/new.txt"])?;
+    run_tree(&["-xdev", "-mindepth", "1", "-maxdepth", "1", "-type", "d"], &["./emptydir", "./sub"])
+}
+
+// --------------------------------------------------
+#[test]
+fn prune() -> TestResult {
+    run_tree(&["-name", "sub", "-prune", "-o", "-type", "f"], &["./big.bin", "./empty.txt", "./small.txt", "./sub"])?;
+    run_tree(&["-depth", "-name", "sub", "-prune", "-o", "-type", "f"], &["./big.bin", "./empty.txt", "./small.txt", "./sub", "./sub/new.txt"])
+}
+
+// --------------------------------------------------
+#[test]
+fn depth_first() -> TestResult {
+    let dir = make_tree()?;
+    let cmd = Command::cargo_bin(PRG)?
+        .current_dir(dir.path())
+        .args([".", "-depth"])
+        .assert()
+        .success();
+    let stdout = String::from_utf8(cmd.get_output().stdout.clone())?;
+    let lines: Vec<&str> =
+        stdout.split('\n').filter(|s| !s.is_empty()).collect();
+    let position = |path| lines.iter().position(|line| *line == path).unwrap();
+    assert!(position("./sub/new.txt") < position("./sub"));
+    assert_eq!(lines.last(), Some(&"."));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+#[cfg(unix)]
+fn follow_links() -> TestResult {
+    let dir = make_tree()?;
+    std::os::unix::fs::symlink("sub", dir.path().join("link"))?;
+    std::os::unix::fs::symlink("..", dir.path().join("sub/loop"))?;
+    let find = |args: &[&str]| -> Result<(Vec<String>, String), Box<dyn std::error::Error>> {
+        let cmd = Command::cargo_bin(PRG)?.current_dir(dir.path()).args(args).assert().success();
+        let out = cmd.get_output();
+        let mut lines: Vec<String> =
+            String::from_utf8(out.stdout.clone())?.split('\n').filter(|s| !s.is_empty()).map(String::from).collect();
+        lines.sort();
+        Ok((lines, String::from_utf8(out.stderr.clone())?))
+    };
+
+    assert_eq!(find(&[".", "-type", "l"])?.0, ["./link", "./sub/loop"]);
+    assert_eq!(find(&["link"])?.0, ["link"]);
+    assert_eq!(find(&["-H", "link"])?.0, ["link", "link/loop", "link/new.txt"]);
+
+    let (lines, stderr) = find(&["-L", ".", "-name", "new.txt"])?;
+    assert_eq!(lines, ["./link/new.txt", "./sub/new.txt"]);
+    assert!(stderr.contains("File system loop found"));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn print0_printf() -> TestResult {
+    let dir = make_tree()?;
+    Command::cargo_bin(PRG)?
+        .current_dir(dir.path())
+        .args([".", "-name", "small.*", "-print0", "-o", "-name", "new.*", "-print0"])
+        .assert()
+        .success()
+        .stdout("./small.txt\0./sub/new.txt\0");
+    Command::cargo_bin(PRG)?
+        .current_dir(dir.path())
+        .args([".", "-name", "small.txt", "-printf", "%p\\t%s %%\\n"])
+        .assert()
+        .success()
+        .stdout("./small.txt\t100 %\n");
+    #[cfg(unix)]
+    Command::cargo_bin(PRG)?
+        .current_dir(dir.path())
+        .args(["sub", "-type", "f", "-printf", "%m %T@\\n"])
+        .assert()
+        .success()
+        .stdout(predicate::str::is_match(r"^644 \d+\.\d{10}\n$")?);
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+#[cfg(unix)]
+fn exec() -> TestResult {
+    let dir = make_tree()?;
+    let find = |args: &[&str]| Command::cargo_bin(PRG).unwrap().current_dir(dir.path()).args(args).assert();
+    find(&["sub", "-type", "f", "-exec", "cat", "{}", ";"]).success().stdout("xxxxxxxxxx");
+    find(&[".", "-type", "f", "-exec", "echo", "files:", "{}", "+"])
+        .success()
+        .stdout(predicate::str::starts_with("files: ./").and(predicate::str::contains("./sub/new.txt")).and(predicate::str::is_match("^[^\n]*\n$")?));
+    find(&["sub", "-exec", "false", ";", "-o", "-print"]).success().stdout("sub\nsub/new.txt\n");
+    find(&["sub", "-exec", "false", "{}", "+"]).failure();
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn delete() -> TestResult {
+    let dir = make_tree()?;
+    Command::cargo_bin(PRG)?
+        .current_dir(dir.path())
+        .args([".", "-name", "*.txt", "-delete"])
+        .assert()
+        .success()
+        .stdout("");
+    let mut left = fs::read_dir(dir.path())?.map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned())).collect::<Result<Vec<_>, io::Error>>()?;
+    left.sort();
+    assert_eq!(left, ["big.bin", "emptydir", "sub"]);
+    assert!(!dir.path().join("sub/new.txt").exists());
+
+    Command::cargo_bin(PRG)?
+        .current_dir(dir.path())
+        .args([".", "(", "-name", "sub", "-o", "-name", "emptydir", ")", "-delete"])
+        .assert()
+        .success();
+    assert!(!dir.path().join("sub").exists());
+    assert!(!dir.path().join("emptydir").exists());
+
+    fs::create_dir(dir.path().join("full"))?;
+    fs::write(dir.path().join("full/keep"), "")?;
+    Command::cargo_bin(PRG)?
+        .current_dir(dir.path())
+        .args([".", "-name", "full", "-delete"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("./full"));
+    assert!(dir.path().join("full/keep").exists());
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn globs() -> TestResult {
+    run_tree(&["-name", "*.txt"], &["./empty.txt", "./small.txt", "./sub/new.txt"])?;
+    run_tree(&["-name", "s*"], &["./small.txt", "./sub"])?;
+    run_tree(&["-name", "b?g.[a-c]in"], &["./big.bin"])?;
+    run_tree(&["-name", "SMALL.TXT"], &[])?;
+    run_tree(&["-iname", "SMALL.TXT"], &["./small.txt"])?;
+    run_tree(&["-path", "./s*t"], &["./small.txt", "./sub/new.txt"])?;
+    run_tree(&["-ipath", "*/SUB/*"], &["./sub/new.txt"])
+}
+
+// --------------------------------------------------
+#[test]
+fn regex() -> TestResult {
+    run_tree(&["-regex", r"\./[a-z]+\.txt"], &["./empty.txt", "./small.txt"])?;
+    run_tree(&["-regex", "s.*"], &[])?;
+    run_tree(&["-regex", ".*/S.*", "-o", "-iregex", ".*/B.*"], &["./big.bin"])
+}
+
+// --------------------------------------------------
+#[test]
+fn no_matches_prints_nothing() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["tests/inputs/a", "tests/inputs/d", "-name", "*.tsv"])
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("tests/inputs").and(predicate::str::ends_with("d.tsv\n")));
+    Command::cargo_bin(PRG)?
+        .args(["tests/inputs", "-name", "nothing"])
+        .assert()
+        .success()
+        .stdout("");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn sorted_and_threaded() -> TestResult {
+    let sorted = Command::cargo_bin(PRG)?
+        .args(["--sort", "path", "tests/inputs"])
+        .output()?
+        .stdout;
+    let lines = String::from_utf8(sorted.clone())?;
+    let lines: Vec<&str> = lines.lines().collect();
+    assert_eq!(lines.len(), 16);
+    let mut paths = lines.iter().map(Path::new).collect::<Vec<_>>();
+    paths.sort();
+    assert_eq!(paths, lines.iter().map(Path::new).collect::<Vec<_>>());
+
+    for threads in ["1", "4"] {
+        Command::cargo_bin(PRG)?
+            .args(["--sort", "path", "-j", threads, "tests/inputs"])
+            .assert()
+            .success()
+            .stdout(sorted.clone());
+    }
+    run(&["-j", "4", "tests/inputs", "-type", "f"], "tests/expected/type_f.txt")?;
+    run(&["--threads", "3", "tests/inputs", "-mindepth", "1", "-maxdepth", "1", "-type", "d"], "tests/expected/type_d_depth1.txt")?;
+    run_tree(&["-j", "2", "-name", "sub", "-prune", "-o", "-type", "f"], &["./big.bin", "./empty.txt", "./small.txt", "./sub"])
+}
+
+// --------------------------------------------------
+#[test]
+#[cfg(unix)]
+fn stops_on_closed_stdout() -> TestResult {
+    let dir = tempfile::tempdir()?;
+    for i in 0..2000 {
+        fs::write(dir.path().join(format!("{:0>60}", i)), "")?;
+    }
+    for threads in ["1", "4"] {
+        let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin(PRG))
+            .args(["-j", threads, &dir.path().to_string_lossy()])
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()?;
+        let mut line = String::new();
+        io::BufRead::read_line(&mut io::BufReader::new(child.stdout.take().unwrap()), &mut line)?;
+        let output = child.wait_with_output()?;
+        assert!(!output.status.success());
+        assert_eq!(String::from_utf8(output.stderr)?.matches("Broken pipe").count(), 1);
+    }
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_threads() -> TestResult {
+    for (args, expected) in [
+        (&["-j", "0"][..], "Invalid thread count \"0\""),
+        (&["-j", "2", ".", "-depth"], "cannot be combined with --threads"),
+        (&["-j", "2", ".", "-delete"], "cannot be combined with --threads"),
+    ] {
+        Command::cargo_bin(PRG)?
+            .args(args)
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(expected));
+    }
+    Ok(())
+}
+
+// --------------------------------------------------
+fn make_content_tree() -> Result<TempDir, Box<dyn std::error::Error>> {
+    let dir = make_tree()?;
+    fs::write(dir.path().join("image.png"), b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR")?;
+    fs::write(dir.path().join("notes.txt"), "first line\nTODO: more\n")?;
+    fs::write(dir.path().join("data.gz"), b"\x1f\x8b\x08\0\0\0\0\0")?;
+    Ok(dir)
+}
+
+// --------------------------------------------------
+#[test]
+fn contents() -> TestResult {
+    run_tree_with(make_content_tree, &["-contains", "^TODO"], &["./notes.txt"])?;
+    run_tree_with(make_content_tree, &["-contains", "x{101}"], &["./big.bin"])?;
+    run_tree_with(make_content_tree, &["-name", "*.txt", "-contains", "x"], &["./small.txt", "./sub/new.txt"])?;
+    run_tree_with(make_content_tree, &["-binary"], &["./data.gz", "./image.png"])?;
+    run_tree_with(make_content_tree, &["-text", "-size", "+0"], &["./big.bin", "./notes.txt", "./small.txt", "./sub/new.txt"])?;
+    run_tree_with(make_content_tree, &["-mime", "image/*"], &["./image.png"])?;
+    run_tree_with(make_content_tree, &["-mime", "application/gzip", "-o", "-mime", "application/x-empty"], &["./data.gz", "./empty.txt"])?;
+    run_tree_with(make_content_tree, &["-type", "d", "-text"], &[])
+}
+
+// --------------------------------------------------
+#[test]
+#[cfg(unix)]
+fn access() -> TestResult {
+    run_tree_with(make_content_tree, &["-type", "f", "-executable"], &["./big.bin"])?;
+    run_tree_with(make_content_tree, &["-readable", "-type", "f", "-name", "*.txt"], &["./empty.txt", "./notes.txt", "./small.txt", "./sub/new.txt"])?;
+    run_tree_with(make_content_tree, &["-writable", "-name", "data.gz"], &["./data.gz"])
+}
+
+// --------------------------------------------------
+fn make_duplicates() -> Result<TempDir, Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    fs::create_dir(dir.path().join("sub"))?;
+    let start = "x".repeat(5000);
+    for (name, contents) in [("a", "same\n"), ("sub/b", "same\n"), ("d", "diff\n"), ("e", &start), ("f", &start), ("g", &format!("{}y", &start[1..])), ("empty1", ""), ("empty2", "")] {
+        fs::write(dir.path().join(name), contents)?;
+    }
+    #[cfg(unix)]
+    fs::hard_link(dir.path().join("a"), dir.path().join("c"))?;
+    #[cfg(unix)]
+    fs::hard_link(dir.path().join("g"), dir.path().join("h"))?;
+    Ok(dir)
+}
+
+// --------------------------------------------------
+#[test]
+#[cfg(unix)]
+fn duplicates() -> TestResult {
+    let dir = make_duplicates()?;
+    Command::cargo_bin(PRG)?
+        .current_dir(dir.path())
+        .arg("--duplicates")
+        .assert()
+        .success()
+        .stdout("./e\n./f\n\n./a\n./c\n./sub/b\n");
+    Command::cargo_bin(PRG)?
+        .current_dir(dir.path())
+        .args(["--duplicates", ".", "-name", "[ab]"])
+        .assert()
+        .success()
+        .stdout("./a\n./sub/b\n");
+    Command::cargo_bin(PRG)?
+        .current_dir(dir.path())
+        .args(["--duplicates", ".", "-name", "[ac]"])
+        .assert()
+        .success()
+        .stdout("");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+#[cfg(unix)]
+fn duplicates_unreadable() -> TestResult {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = make_duplicates()?;
+    let path = dir.path().join("f");
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o000))?;
+    // Permissions do not stop root from reading the file.
+    if fs::read(&path).is_ok() {
+        return Ok(());
+    }
+    Command::cargo_bin(PRG)?
+        .current_dir(dir.path())
+        .arg("--duplicates")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("./f: Permission denied"))
+        .stdout("./a\n./c\n./sub/b\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+#[cfg(unix)]
+fn duplicates_json() -> TestResult {
+    let dir = make_duplicates()?;
+    let cmd = Command::cargo_bin(PRG)?
+        .current_dir(dir.path())
+        .args(["--duplicates", "--json"])
+        .assert()
+        .success();
+    let stdout = String::from_utf8(cmd.get_output().stdout.clone())?;
+    let lines: Vec<serde_json::Value> = stdout.lines().map(serde_json::from_str).collect::<Result<_, _>>()?;
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0]["type"], "duplicates");
+    assert_eq!(lines[0]["data"]["size"], 5000);
+    assert_eq!(lines[0]["data"]["paths"], serde_json::json!(["./e", "./f"]));
+    assert_eq!(lines[0]["data"]["hash"].as_str().map(str::len), Some(64));
+    assert_eq!(lines[1]["data"]["files"], 2);
+    assert_eq!(lines[1]["data"]["paths"], serde_json::json!(["./a", "./c", "./sub/b"]));
+    assert_eq!(lines[2], serde_json::json!({ "type": "summary", "data": { "groups": 2, "files": 4, "reclaimable_bytes": 5005 } }));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_duplicates() -> TestResult {
+    for (args, expected) in [
+        (&["--duplicates", ".", "-print"][..], "--duplicates cannot be combined with actions"),
+        (&["--duplicates", "-j", "2"], "--duplicates cannot be combined with --threads"),
+        (&["--json"], "--duplicates"),
+    ] {
+        Command::cargo_bin(PRG)?
+            .args(args)
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(expected));
+    }
+    Ok(())
+}
@@ -1,19 +1,174 @@
-use std::{error::Error, fs::{self, File}, io::{self, BufRead, BufReader}, mem};
+mod matcher;
 
-use clap::{App, Arg};
-use regex::{Regex, RegexBuilder};
-use walkdir::WalkDir;
+use std::{collections::{BTreeMap, VecDeque}, env, error::Error, fs::{self, File, Metadata}, io::{self, BufRead, BufReader, IsTerminal, Read, Seek, SeekFrom, Write}, mem, ops::{Deref, DerefMut}, path::Path, sync::atomic::{AtomicBool, AtomicUsize, Ordering}, thread, time::Duration};
+
+use ansi_term::{Colour, Style};
+use bzip2::read::MultiBzDecoder;
+use chrono::Local;
+use clap::{App, Arg, ErrorKind};
+use flate2::bufread::MultiGzDecoder;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::{WalkBuilder, WalkState};
+use matcher::{Matcher, MatcherOptions};
+use memmap2::Mmap;
+use serde_json::{json, Value};
+use tar::Archive;
+use tempfile::Builder;
+use xz2::bufread::XzDecoder;
 
 
 type MyResult<T> = Result<T, Box<dyn Error>>;
 
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(250);
+
+// Prefix of the temporary files --in-place writes next to the files it
+// edits; the walk skips them so they are never searched themselves.
+const EDIT_PREFIX: &str = ".grepr-edit-";
+
 #[derive(Debug)]
 pub struct Config {
-    pattern: Regex,
+    pattern: Box<dyn Matcher>,
     files: Vec<String>,
-    recursive: bool,
+    walk: WalkOptions,
     count: bool,
-    invert_match: bool
+    invert_match: bool,
+    context: Context,
+    line_number: bool,
+    byte_offset: bool,
+    column: bool,
+    only_matching: bool,
+    with_filename: Option<bool>,
+    colors: Colors,
+    files_with_matches: bool,
+    files_without_match: bool,
+    max_count: Option<usize>,
+    quiet: bool,
+    binary_files: BinaryFiles,
+    search_zip: bool,
+    json: bool,
+    replace: Option<Vec<u8>>,
+    in_place: bool,
+    dry_run: bool,
+    backup: Option<String>,
+    break_links: bool,
+    multiline: bool,
+    follow: bool,
+    timestamps: bool
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryFiles {
+    Binary,
+    Text,
+    WithoutMatch
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct WalkOptions {
+    recursive: bool,
+    no_ignore: bool,
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+    sort: bool,
+    threads: usize
+}
+
+// How a run ended, which main turns into grep's exit status: 0 when a line
+// was selected, 1 when none was and 2 when an error occurred, unless -q had
+// already found a match.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Matched,
+    NoMatch,
+    Failed
+}
+
+impl Outcome {
+    fn new(config: &Config, matched: bool, errored: bool) -> Outcome {
+        if errored && !(config.quiet && matched) {
+            Outcome::Failed
+        } else if matched {
+            Outcome::Matched
+        } else {
+            Outcome::NoMatch
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Searched {
+    num_matches: usize,
+    submatches: usize
+}
+
+// Files are printed in the order they were found. The file whose turn it is
+// writes straight to stdout as it is searched, so matches from a slow stream
+// show up right away; later files are held in their Output until then.
+#[derive(Debug)]
+struct Printer {
+    turn: AtomicUsize,
+    printed: AtomicBool,
+    separator: Option<String>
+}
+
+#[derive(Debug)]
+struct Output<'a> {
+    printer: &'a Printer,
+    index: usize,
+    buf: Vec<u8>,
+    started: bool
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Colors {
+    selected_match: Style,
+    context_match: Style,
+    filename: Style,
+    line_number: Style,
+    byte_offset: Style,
+    separator: Style
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Context {
+    before: usize,
+    after: usize
+}
+
+#[derive(Debug, PartialEq)]
+struct Line<'a> {
+    number: usize,
+    offset: usize,
+    text: &'a [u8]
+}
+
+enum Input<'a> {
+    Stream(Box<dyn BufRead + 'a>),
+    Mapped(&'a [u8])
+}
+
+#[derive(Debug)]
+struct Follower {
+    name: String,
+    file: File,
+    id: Option<(u64, u64)>,
+    offset: u64,
+    number: Option<usize>,
+    partial: Vec<u8>
+}
+
+#[derive(Debug, PartialEq)]
+struct Change<'a> {
+    number: usize,
+    old: &'a [u8],
+    new: Vec<u8>
+}
+
+#[derive(Debug, PartialEq)]
+enum Event<'a> {
+    Match(Line<'a>),
+    Context(Line<'a>),
+    Break
 }
 
 pub fn get_args() -> MyResult<Config> {
@@ -21,89 +176,946 @@ pub fn get_args() -> MyResult<Config> {
         .version("0.1.0")
         .author("Hajime Nakamura <h.nakamura0903@gmail.com>")
         .about("Rust grep")
-        .arg(Arg::with_name("pattern").value_name("PATTERN").help("Search pattern").required(true))
+        .arg(Arg::with_name("pattern").value_name("PATTERN").help("Search pattern").required_unless_one(&["regexp", "file"]))
         .arg(Arg::with_name("files").value_name("FILE").help("Input file(s)").multiple(true).default_value("-"))
+        .arg(Arg::with_name("regexp").value_name("PATTERN").short("e").long("regexp").help("Search pattern (repeatable)").multiple(true).number_of_values(1))
+        .arg(Arg::with_name("file").value_name("FILE").short("f").long("file").help("Read patterns from FILE").multiple(true).number_of_values(1))
+        .arg(Arg::with_name("fixed_strings").short("F").long("fixed-strings").help("Patterns are fixed strings").takes_value(false))
+        .arg(Arg::with_name("perl").short("P").long("perl-regexp").help("Patterns are Perl-compatible regular expressions (lookaround, backreferences)").takes_value(false).conflicts_with("fixed_strings"))
+        .arg(Arg::with_name("multiline").short("U").long("multiline").help("Allow matches to span lines").takes_value(false).conflicts_with_all(&["invert", "after", "before", "context", "in_place"]))
+        .arg(Arg::with_name("word").short("w").long("word-regexp").help("Match whole words only").takes_value(false))
+        .arg(Arg::with_name("line").short("x").long("line-regexp").help("Match whole lines only").takes_value(false))
         .arg(Arg::with_name("insensitive").short("i").long("insensitive").help("Case-insensitive").takes_value(false))
         .arg(Arg::with_name("recursive").short("r").long("recursive").help("Recursive search").takes_value(false))
+        .arg(Arg::with_name("no_ignore").long("no-ignore").help("Search ignored and hidden files").takes_value(false))
+        .arg(Arg::with_name("include").value_name("GLOB").long("include").help("Search only files matching GLOB").multiple(true).number_of_values(1))
+        .arg(Arg::with_name("exclude").value_name("GLOB").long("exclude").help("Skip files matching GLOB").multiple(true).number_of_values(1))
+        .arg(Arg::with_name("sort").value_name("SORTBY").long("sort").help("Sort output").possible_values(&["path", "none"]).default_value("none"))
+        .arg(Arg::with_name("threads").value_name("NUM").short("j").long("threads").help("Number of search threads"))
         .arg(Arg::with_name("count").short("c").long("count").help("Count occurrences").takes_value(false))
         .arg(Arg::with_name("invert").short("v").long("invert-match").help("Invert match").takes_value(false))
-        .get_matches();
+        .arg(Arg::with_name("after").value_name("NUM").short("A").long("after-context").help("Print NUM lines of trailing context"))
+        .arg(Arg::with_name("before").value_name("NUM").short("B").long("before-context").help("Print NUM lines of leading context"))
+        .arg(Arg::with_name("context").value_name("NUM").short("C").long("context").help("Print NUM lines of output context"))
+        .arg(Arg::with_name("line_number").short("n").long("line-number").help("Print line numbers").takes_value(false))
+        .arg(Arg::with_name("byte_offset").short("b").long("byte-offset").help("Print byte offsets").takes_value(false))
+        .arg(Arg::with_name("column").long("column").help("Print column of first match").takes_value(false))
+        .arg(Arg::with_name("only_matching").short("o").long("only-matching").help("Print only matched parts").takes_value(false))
+        .arg(Arg::with_name("with_filename").short("H").long("with-filename").help("Print filenames").takes_value(false).overrides_with("no_filename"))
+        .arg(Arg::with_name("no_filename").short("h").long("no-filename").help("Suppress filenames").takes_value(false).overrides_with("with_filename"))
+        .arg(Arg::with_name("files_with_matches").short("l").long("files-with-matches").help("Print only names of files with matches").takes_value(false).conflicts_with("files_without_match"))
+        .arg(Arg::with_name("files_without_match").short("L").long("files-without-match").help("Print only names of files without matches").takes_value(false))
+        .arg(Arg::with_name("max_count").value_name("NUM").short("m").long("max-count").help("Stop reading a file after NUM matching lines"))
+        .arg(Arg::with_name("quiet").short("q").long("quiet").alias("silent").help("Print nothing, exit zero on first match").takes_value(false))
+        .arg(Arg::with_name("search_zip").short("z").long("search-zip").help("Search compressed files and tar archives").takes_value(false))
+        .arg(Arg::with_name("text").short("a").long("text").help("Process binary files as text").takes_value(false))
+        .arg(Arg::with_name("binary_files").value_name("TYPE").long("binary-files").help("How to handle binary files").possible_values(&["binary", "text", "without-match"]).default_value("binary"))
+        .arg(Arg::with_name("json").long("json").help("Print results as JSON lines").takes_value(false).conflicts_with_all(&["count", "only_matching", "files_with_matches", "files_without_match", "quiet"]))
+        .arg(Arg::with_name("replace").value_name("TEMPLATE").long("replace").help("Replace matches with TEMPLATE ($1, ${name})"))
+        .arg(Arg::with_name("in_place").long("in-place").help("Write replacements back to the files").takes_value(false).requires("replace").conflicts_with_all(&["invert", "only_matching", "json", "search_zip", "count", "files_with_matches", "files_without_match"]))
+        .arg(Arg::with_name("dry_run").long("dry-run").help("Show a diff instead of editing files").takes_value(false).requires("in_place"))
+        .arg(Arg::with_name("backup").value_name("SUFFIX").long("backup").help("Keep a copy of each edited file with SUFFIX").requires("in_place"))
+        .arg(Arg::with_name("break_links").long("break-links").help("Edit files that have other hard links, leaving those links unchanged").takes_value(false).requires("in_place"))
+        .arg(Arg::with_name("follow").long("follow").help("Keep reading lines appended to the files").takes_value(false).conflicts_with_all(&["recursive", "count", "only_matching", "files_with_matches", "files_without_match", "json", "in_place", "multiline", "search_zip", "after", "before", "context"]))
+        .arg(Arg::with_name("timestamps").long("timestamps").help("Prefix followed lines with the time they were read").takes_value(false).requires("follow"))
+        .arg(Arg::with_name("color").value_name("WHEN").long("color").alias("colour").help("Highlight matches").possible_values(&["auto", "always", "never"]).default_value("auto"))
+        .get_matches_safe()
+        .or_else(|e| match e.kind {
+            ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => e.exit(),
+            // Usage errors go back to main to exit 2 like grep, not clap's 1.
+            _ => Err(e),
+        })?;
 
-    let pattern = matches.value_of("pattern").unwrap();
-    let pattern = RegexBuilder::new(pattern).case_insensitive(matches.is_present("insensitive")).build().map_err(|_| format!("Invalid pattern \"{}\"", pattern))?;
+    let mut files = matches.values_of_lossy("files").unwrap();
+    let mut patterns = matches.values_of_lossy("regexp").unwrap_or_default();
+    for filename in matches.values_of_lossy("file").unwrap_or_default() {
+        let contents = fs::read_to_string(&filename).map_err(|e| format!("{}: {}", filename, e))?;
+        patterns.extend(contents.lines().map(String::from));
+    }
+    if let Some(pattern) = matches.value_of("pattern") {
+        if matches.is_present("regexp") || matches.is_present("file") {
+            if matches.occurrences_of("files") == 0 {
+                files.clear();
+            }
+            files.insert(0, pattern.to_string());
+        } else {
+            patterns.push(pattern.to_string());
+        }
+    }
+    let pattern = matcher::new(&patterns, MatcherOptions {
+        fixed_strings: matches.is_present("fixed_strings"),
+        insensitive: matches.is_present("insensitive"),
+        word: matches.is_present("word"),
+        line: matches.is_present("line"),
+        perl: matches.is_present("perl"),
+        multiline: matches.is_present("multiline"),
+    })?;
+    let use_color = match matches.value_of("color") {
+        Some("always") => true,
+        Some("never") => false,
+        _ => env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()) && io::stdout().is_terminal(),
+    };
+    let colors = if use_color {
+        parse_grep_colors(&env::var("GREP_COLORS").unwrap_or_default())
+    } else {
+        Colors::default()
+    };
+    let max_count = matches.value_of("max_count").map(|val| val.parse().map_err(|_| format!("Invalid max count \"{}\"", val))).transpose()?;
+    let threads = match matches.value_of("threads") {
+        Some(val) => val.parse().ok().filter(|&n| n > 0).ok_or_else(|| format!("Invalid thread count \"{}\"", val))?,
+        None => thread::available_parallelism().map_or(1, usize::from),
+    };
+    let walk = WalkOptions {
+        recursive: matches.is_present("recursive"),
+        no_ignore: matches.is_present("no_ignore"),
+        include: matches.values_of_lossy("include").map(|globs| build_globs(&globs)).transpose()?,
+        exclude: matches.values_of_lossy("exclude").map(|globs| build_globs(&globs)).transpose()?,
+        sort: matches.value_of("sort") == Some("path"),
+        threads,
+    };
+    let context = matches.value_of("context").map(parse_context).transpose()?.unwrap_or(0);
+    let context = Context {
+        before: matches.value_of("before").map(parse_context).transpose()?.unwrap_or(context),
+        after: matches.value_of("after").map(parse_context).transpose()?.unwrap_or(context),
+    };
     Ok(Config { 
-        pattern: pattern, 
-        files: matches.values_of_lossy("files").unwrap(),
-        recursive: matches.is_present("recursive"), 
+        pattern, 
+        files,
+        walk,
         count: matches.is_present("count"), 
         invert_match: matches.is_present("invert"), 
+        context,
+        line_number: matches.is_present("line_number"),
+        byte_offset: matches.is_present("byte_offset"),
+        column: matches.is_present("column"),
+        only_matching: matches.is_present("only_matching"),
+        with_filename: if matches.is_present("with_filename") {
+            Some(true)
+        } else if matches.is_present("no_filename") {
+            Some(false)
+        } else {
+            None
+        },
+        colors,
+        files_with_matches: matches.is_present("files_with_matches"),
+        files_without_match: matches.is_present("files_without_match"),
+        max_count,
+        quiet: matches.is_present("quiet"),
+        binary_files: match matches.value_of("binary_files") {
+            _ if matches.is_present("text") => BinaryFiles::Text,
+            Some("text") => BinaryFiles::Text,
+            Some("without-match") => BinaryFiles::WithoutMatch,
+            _ => BinaryFiles::Binary,
+        },
+        search_zip: matches.is_present("search_zip"),
+        json: matches.is_present("json"),
+        replace: matches.value_of("replace").map(|template| template.as_bytes().to_vec()),
+        in_place: matches.is_present("in_place"),
+        dry_run: matches.is_present("dry_run"),
+        backup: matches.value_of("backup").map(String::from),
+        break_links: matches.is_present("break_links"),
+        multiline: matches.is_present("multiline"),
+        follow: matches.is_present("follow"),
+        timestamps: matches.is_present("timestamps"),
     })
 
 }
 
-pub fn run(config: Config) -> MyResult<()> {
-    let entries = find_files(&config.files, config.recursive);
-    let num_files = entries.len();
-    let print = |fname: &str, val: &str| {
-        if num_files > 1 {
-            print!("{}:{}", fname, val);
-        } else {
-            print!("{}", val)
+pub fn run(config: Config) -> MyResult<Outcome> {
+    if config.follow {
+        return follow(&config);
+    }
+    let show_filename = config.with_filename.unwrap_or_else(|| {
+        config.files.len() > 1 || (config.walk.recursive && config.files.iter().any(|path| Path::new(path).is_dir()))
+    });
+    let separate = !(config.quiet || config.count || config.only_matching || config.files_with_matches || config.files_without_match || config.json) && config.context != Context::default();
+    let printer = Printer {
+        turn: AtomicUsize::new(0),
+        printed: AtomicBool::new(false),
+        separator: separate.then(|| config.colors.separator.paint("--").to_string()),
+    };
+    let quit = AtomicBool::new(false);
+    let next_index = AtomicUsize::new(0);
+    let (job_tx, job_rx) = crossbeam_channel::bounded::<(usize, Result<String, String>)>(config.walk.threads * 4);
+    let (result_tx, result_rx) = crossbeam_channel::unbounded::<(Output, Result<Searched, String>)>();
+    let (config, printer, quit, next_index) = (&config, &printer, &quit, &next_index);
+
+    let (success, errored) = thread::scope(|s| {
+        s.spawn(move || {
+            find_files(&config.files, &config.walk, |entry| {
+                !quit.load(Ordering::Relaxed) && job_tx.send((next_index.fetch_add(1, Ordering::SeqCst), entry)).is_ok()
+            });
+        });
+        for _ in 0..config.walk.threads {
+            let (job_rx, result_tx) = (job_rx.clone(), result_tx.clone());
+            s.spawn(move || {
+                for (i, entry) in job_rx {
+                    if quit.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let mut out = Output::new(printer, i);
+                    let result = entry.and_then(|filename| search_file(config, &filename, show_filename, &mut out));
+                    if config.quiet && result.as_ref().is_ok_and(|searched| searched.num_matches > 0) {
+                        quit.store(true, Ordering::Relaxed);
+                    }
+                    if result_tx.send((out, result)).is_err() {
+                        break;
+                    }
+                }
+            });
         }
+        drop((job_rx, result_tx));
+
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        let mut success = false;
+        let mut errored = false;
+        let (mut searches, mut searches_with_match, mut matched_lines, mut submatches) = (0, 0, 0, 0);
+        for (out, result) in result_rx {
+            pending.insert(out.index, (out, result));
+            while let Some((mut out, result)) = pending.remove(&next) {
+                out.flush()?;
+                match result {
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        errored = true;
+                    }
+                    Ok(searched) => {
+                        success |= searched.num_matches > 0;
+                        searches += 1;
+                        searches_with_match += usize::from(searched.num_matches > 0);
+                        matched_lines += searched.num_matches;
+                        submatches += searched.submatches;
+                    }
+                }
+                next += 1;
+                printer.turn.store(next, Ordering::SeqCst);
+            }
+        }
+        if config.json {
+            let mut stdout = io::stdout().lock();
+            let stats = json!({ "searches": searches, "searches_with_match": searches_with_match, "matched_lines": matched_lines, "matches": submatches });
+            writeln!(stdout, "{}", json!({ "type": "summary", "data": { "stats": stats } }))?;
+        }
+        Ok::<_, io::Error>((success, errored))
+    })?;
+
+    Ok(Outcome::new(config, success, errored))
+}
+
+fn search_file(config: &Config, filename: &str, show_filename: bool, out: &mut Output) -> Result<Searched, String> {
+    if config.in_place {
+        return edit_file(config, filename, out).map_err(|e| format!("{}: {}", filename, e));
+    }
+    if config.multiline && !config.search_zip && filename != "-" {
+        let map = map_file(filename).map_err(|e| format!("{}: {}", filename, e))?;
+        return search_reader(config, filename, Input::Mapped(map.as_deref().unwrap_or_default()), show_filename, out);
+    }
+    let file = open(filename).map_err(|e| format!("{}: {}", filename, e))?;
+    if !config.search_zip {
+        return search_reader(config, filename, Input::Stream(file), show_filename, out);
+    }
+    let file = decompress(file).map_err(|e| format!("{}: {}", filename, e))?;
+    if !is_tar(filename) {
+        return search_reader(config, filename, Input::Stream(file), show_filename, out);
+    }
+
+    let mut searched = Searched::default();
+    let mut archive = Archive::new(file);
+    for entry in archive.entries().map_err(|e| format!("{}: {}", filename, e))? {
+        let entry = entry.map_err(|e| format!("{}: {}", filename, e))?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = match entry.path() {
+            Ok(path) => format!("{}:{}", filename, path.display()),
+            Err(e) => return Err(format!("{}: {}", filename, e)),
+        };
+        let member = search_reader(config, &name, Input::Stream(Box::new(BufReader::new(entry))), true, out)?;
+        searched.num_matches += member.num_matches;
+        searched.submatches += member.submatches;
+        if config.quiet && searched.num_matches > 0 {
+            break;
+        }
+    }
+    Ok(searched)
+}
+
+fn search_reader(config: &Config, filename: &str, mut input: Input, show_filename: bool, out: &mut Output) -> Result<Searched, String> {
+    let binary = config.binary_files != BinaryFiles::Text && input.is_binary().map_err(|e| format!("{}: {}", filename, e))?;
+    let colors = &config.colors;
+    let prefix = |sep: char, number: usize, column: Option<usize>, offset: usize| {
+        line_prefix(config, show_filename.then_some(filename), sep, number, column, offset)
     };
+    let list_files = config.files_with_matches || config.files_without_match;
+    let quiet = config.quiet || config.count || list_files;
+    let summarize = binary && !quiet && !config.json;
+    let context = if quiet || summarize || config.only_matching { Context::default() } else { config.context };
+    let max_count = if config.quiet || list_files || summarize { Some(config.max_count.map_or(1, |max| max.min(1))) } else { config.max_count };
 
-    for entry in entries {
-        match entry {
-            Err(e) => eprintln!("{}", e),
-            Ok(filename) => match open(&filename) {
-                Err(e) => eprintln!("{}: {}", filename, e),
-                Ok(file) => {
-                    match find_lines(file, &config.pattern, config.invert_match) {
-                        Err(e) => eprintln!("{}", e),
-                        Ok(matches) => {
-                            if config.count {
-                                print(&filename, &format!("{}\n", matches.len()))
-                            } else {
-                                for line in &matches {
-                                    print(&filename, line)
-                                }
-                            }
-                        }
+    let mut pending_break = false;
+    let mut began = false;
+    let mut submatches = 0;
+    let num_matches = if binary && config.binary_files == BinaryFiles::WithoutMatch { 0 } else { scan(input, config.pattern.as_ref(), config.invert_match, &context, max_count, config.multiline, |event| {
+        if quiet || summarize {
+            return Ok(());
+        }
+        if config.json {
+            let (kind, line) = match event {
+                Event::Match(line) => ("match", line),
+                Event::Context(line) => ("context", line),
+                Event::Break => return Ok(()),
+            };
+            if !mem::replace(&mut began, true) {
+                writeln_json(out, json!({ "type": "begin", "data": { "path": { "text": filename } } }));
+            }
+            let spans = if kind == "match" && !config.invert_match {
+                config.pattern.find_iter(trim_newline(line.text)).collect::<Result<Vec<_>, _>>()?.into_iter().filter(|m| !m.is_empty()).map(|m| json!({
+                    "match": { "text": String::from_utf8_lossy(&line.text[m.clone()]) },
+                    "start": m.start,
+                    "end": m.end,
+                })).collect()
+            } else {
+                vec![]
+            };
+            submatches += spans.len();
+            writeln_json(out, json!({ "type": kind, "data": {
+                "path": { "text": filename },
+                "lines": { "text": String::from_utf8_lossy(line.text) },
+                "line_number": line.number,
+                "absolute_offset": line.offset,
+                "submatches": spans,
+            } }));
+            return Ok(out.flush()?);
+        }
+        if let Event::Break = event {
+            pending_break = true;
+            return Ok(());
+        }
+        if mem::take(&mut pending_break) {
+            out.extend(format!("{}\n", colors.separator.paint("--")).bytes());
+        }
+        match event {
+            Event::Match(line) if config.only_matching => {
+                let text = trim_newline(line.text);
+                let parts = match &config.replace {
+                    Some(template) => config.pattern.replacements(text, template)?,
+                    None => config.pattern.find_iter(text).map(|m| m.map(|m| (m.clone(), text[m].to_vec()))).collect::<Result<_, _>>()?,
+                };
+                for (m, part) in parts.into_iter().filter(|(m, _)| !m.is_empty()) {
+                    out.extend(prefix(':', line.number, Some(m.start + 1), line.offset + m.start).bytes());
+                    out.extend(paint(colors.selected_match, &part));
+                    out.push(b'\n');
+                }
+            }
+            Event::Match(line) => {
+                let column = config.pattern.find(trim_newline(line.text))?.filter(|_| !config.invert_match).map(|m| m.start + 1);
+                out.extend(prefix(':', line.number, column, line.offset).bytes());
+                out.extend(match &config.replace {
+                    _ if config.invert_match => line.text.to_vec(),
+                    Some(template) => replace(line.text, config.pattern.as_ref(), template, colors.selected_match)?,
+                    None => highlight(line.text, config.pattern.as_ref(), colors.selected_match)?,
+                });
+            }
+            Event::Context(line) => {
+                out.extend(prefix('-', line.number, None, line.offset).bytes());
+                out.extend(if config.invert_match { highlight(line.text, config.pattern.as_ref(), colors.context_match)? } else { line.text.to_vec() });
+            }
+            Event::Break => {}
+        }
+        Ok(out.flush()?)
+    }).map_err(|e| format!("{}: {}", filename, e))? };
+
+    if config.quiet {
+    } else if config.json {
+        if began {
+            writeln_json(out, json!({ "type": "end", "data": { "path": { "text": filename }, "stats": { "matched_lines": num_matches, "matches": submatches } } }));
+        }
+    } else if list_files {
+        if (config.files_with_matches && num_matches > 0) || (config.files_without_match && num_matches == 0) {
+            out.extend(format!("{}\n", colors.filename.paint(filename)).bytes());
+        }
+    } else if config.count {
+        let fname = if show_filename { format!("{}{}", colors.filename.paint(filename), colors.separator.paint(":")) } else { String::new() };
+        out.extend(format!("{}{}\n", fname, num_matches).bytes());
+    } else if summarize && num_matches > 0 {
+        out.extend(format!("Binary file {} matches\n", filename).bytes());
+    }
+    Ok(Searched { num_matches, submatches })
+}
+
+fn writeln_json(out: &mut Vec<u8>, value: Value) {
+    out.extend(value.to_string().bytes());
+    out.push(b'\n');
+}
+
+impl Output<'_> {
+    fn new(printer: &Printer, index: usize) -> Output<'_> {
+        Output { printer, index, buf: vec![], started: false }
+    }
+
+    // Writes out what has been buffered if it is this file's turn, putting
+    // the separator before the first output of every file but the first.
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() || self.printer.turn.load(Ordering::SeqCst) != self.index {
+            return Ok(());
+        }
+        let mut stdout = io::stdout().lock();
+        if !mem::replace(&mut self.started, true) && self.printer.printed.swap(true, Ordering::SeqCst) {
+            if let Some(separator) = &self.printer.separator {
+                writeln!(stdout, "{}", separator)?;
+            }
+        }
+        stdout.write_all(&self.buf)?;
+        stdout.flush()?;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+impl Deref for Output<'_> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+
+impl DerefMut for Output<'_> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
+
+fn line_prefix(config: &Config, filename: Option<&str>, sep: char, number: usize, column: Option<usize>, offset: usize) -> String {
+    let colors = &config.colors;
+    let sep = colors.separator.paint(sep.to_string());
+    let mut prefix = String::new();
+    if let Some(filename) = filename {
+        prefix += &format!("{}{}", colors.filename.paint(filename), sep);
+    }
+    if config.line_number {
+        prefix += &format!("{}{}", colors.line_number.paint(number.to_string()), sep);
+    }
+    if let Some(column) = column.filter(|_| config.column) {
+        prefix += &format!("{}{}", colors.line_number.paint(column.to_string()), sep);
+    }
+    if config.byte_offset {
+        prefix += &format!("{}{}", colors.byte_offset.paint(offset.to_string()), sep);
+    }
+    prefix
+}
+
+fn follow(config: &Config) -> MyResult<Outcome> {
+    if config.files.iter().any(|filename| filename == "-") {
+        return Err(From::from("cannot follow standard input"));
+    }
+    let mut errored = false;
+    let mut followers = config.files.iter().filter_map(|filename| match Follower::open(filename, config.line_number) {
+        Ok(follower) => Some(follower),
+        Err(e) => {
+            eprintln!("{}: {}", filename, e);
+            errored = true;
+            None
+        }
+    }).collect::<Vec<_>>();
+    if followers.is_empty() || config.max_count == Some(0) {
+        return Ok(Outcome::new(config, false, errored));
+    }
+
+    let show_filename = config.with_filename.unwrap_or(config.files.len() > 1);
+    let mut num_matches = 0;
+    loop {
+        let mut stdout = io::stdout().lock();
+        for follower in &mut followers {
+            let lines = match follower.poll() {
+                Ok(lines) => lines,
+                Err(e) => {
+                    eprintln!("{}: {}", follower.name, e);
+                    errored = true;
+                    continue;
+                }
+            };
+            for (number, offset, text) in lines {
+                let (column, text) = match follow_line(config, text) {
+                    Ok(Some(selected)) => selected,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        eprintln!("{}: {}", follower.name, e);
+                        errored = true;
+                        continue;
                     }
+                };
+                num_matches += 1;
+                if !config.quiet {
+                    if config.timestamps {
+                        write!(stdout, "{} ", Local::now().format("%Y-%m-%dT%H:%M:%S"))?;
+                    }
+                    write!(stdout, "{}", line_prefix(config, show_filename.then_some(follower.name.as_str()), ':', number, column, offset))?;
+                    stdout.write_all(&text)?;
+                }
+                if config.quiet || config.max_count.is_some_and(|max| num_matches >= max) {
+                    stdout.flush()?;
+                    return Ok(Outcome::new(config, true, errored));
                 }
             }
         }
+        stdout.flush()?;
+        drop(stdout);
+        thread::sleep(FOLLOW_INTERVAL);
+    }
+}
+
+// The column of the first match and the text to print for a selected line.
+type Selected = (Option<usize>, Vec<u8>);
+
+fn follow_line(config: &Config, text: Vec<u8>) -> Result<Option<Selected>, String> {
+    let column = config.pattern.find(trim_newline(&text))?;
+    if column.is_some() == config.invert_match {
+        return Ok(None);
+    }
+    let column = column.filter(|_| !config.invert_match).map(|m| m.start + 1);
+    Ok(Some((column, match &config.replace {
+        _ if config.invert_match => text,
+        Some(template) => replace(&text, config.pattern.as_ref(), template, config.colors.selected_match)?,
+        None => highlight(&text, config.pattern.as_ref(), config.colors.selected_match)?,
+    })))
+}
+
+#[cfg(unix)]
+fn hard_links(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink()
+}
+
+#[cfg(not(unix))]
+fn hard_links(_metadata: &Metadata) -> u64 {
+    1
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+impl Follower {
+    // Starts at the end of the file like tail -f, keeping its unterminated
+    // last line so that it is matched whole once the rest is appended. The
+    // existing lines are only counted for -n; otherwise line numbers are
+    // unknown (reported as 0) until the file is read again from the start.
+    fn open(name: &str, count_lines: bool) -> io::Result<Follower> {
+        let mut file = File::open(name)?;
+        let id = file_id(&file.metadata()?);
+        let offset = file.seek(SeekFrom::End(0))?;
+        let number = if count_lines { Some(Follower::count_lines(&file, offset)?) } else { None };
+        let partial = Follower::last_line(&mut file, offset)?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Follower { name: name.to_string(), file, id, offset, number, partial })
+    }
+
+    // Counts the lines before `end` only, as anything appended while they
+    // are counted is read by the first poll.
+    fn count_lines(mut file: &File, end: u64) -> io::Result<usize> {
+        file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(file.take(end));
+        let mut number = 0;
+        loop {
+            let buf = reader.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(number);
+            }
+            number += buf.iter().filter(|&&b| b == b'\n').count();
+            let len = buf.len();
+            reader.consume(len);
+        }
+    }
+
+    fn last_line(file: &mut File, end: u64) -> io::Result<Vec<u8>> {
+        let mut partial = vec![];
+        let mut pos = end;
+        while pos > 0 {
+            let len = pos.min(8192);
+            pos -= len;
+            let mut buf = vec![0; len as usize];
+            file.seek(SeekFrom::Start(pos))?;
+            file.read_exact(&mut buf)?;
+            let newline = buf.iter().rposition(|&b| b == b'\n');
+            buf.drain(..newline.map_or(0, |i| i + 1));
+            buf.extend(partial);
+            partial = buf;
+            if newline.is_some() {
+                break;
+            }
+        }
+        Ok(partial)
+    }
+
+    // Returns the complete lines appended since the last poll as
+    // (line number, byte offset, text). A file that shrank is read again
+    // from the start, and a file replaced at the same path (rotation) is
+    // drained and then reopened.
+    fn poll(&mut self) -> io::Result<Vec<(usize, usize, Vec<u8>)>> {
+        if self.file.metadata()?.len() < self.offset {
+            eprintln!("{}: file truncated", self.name);
+            self.rewind(None)?;
+        }
+        let mut lines = self.read_lines()?;
+        let rotated = fs::metadata(&self.name).ok().filter(|metadata| self.id.is_some() && file_id(metadata) != self.id);
+        if let Some(metadata) = rotated {
+            self.rewind(Some((File::open(&self.name)?, file_id(&metadata))))?;
+            lines.extend(self.read_lines()?);
+        }
+        Ok(lines)
+    }
+
+    fn rewind(&mut self, reopened: Option<(File, Option<(u64, u64)>)>) -> io::Result<()> {
+        match reopened {
+            Some((file, id)) => {
+                self.file = file;
+                self.id = id;
+            }
+            None => {
+                self.file.seek(SeekFrom::Start(0))?;
+            }
+        }
+        self.offset = 0;
+        self.number = Some(0);
+        self.partial.clear();
+        Ok(())
+    }
+
+    fn read_lines(&mut self) -> io::Result<Vec<(usize, usize, Vec<u8>)>> {
+        let start = self.offset as usize - self.partial.len();
+        let read = self.file.read_to_end(&mut self.partial)?;
+        self.offset += read as u64;
+
+        let mut lines = vec![];
+        let mut line_start = 0;
+        while let Some(i) = self.partial[line_start..].iter().position(|&b| b == b'\n') {
+            let end = line_start + i + 1;
+            self.number = self.number.map(|number| number + 1);
+            lines.push((self.number.unwrap_or(0), start + line_start, self.partial[line_start..end].to_vec()));
+            line_start = end;
+        }
+        self.partial.drain(..line_start);
+        Ok(lines)
+    }
+}
+
+fn decompress(mut file: Box<dyn BufRead>) -> io::Result<Box<dyn BufRead>> {
+    let magic = file.fill_buf()?;
+    Ok(if magic.starts_with(b"\x1f\x8b") {
+        Box::new(BufReader::new(MultiGzDecoder::new(file)))
+    } else if magic.starts_with(b"BZh") {
+        Box::new(BufReader::new(MultiBzDecoder::new(file)))
+    } else if magic.starts_with(b"\xfd7zXZ\x00") {
+        Box::new(BufReader::new(XzDecoder::new_multi_decoder(file)))
+    } else if magic.starts_with(b"\x28\xb5\x2f\xfd") {
+        Box::new(BufReader::new(zstd::Decoder::with_buffer(file)?))
+    } else {
+        file
+    })
+}
+
+fn is_tar(filename: &str) -> bool {
+    let name = filename.to_lowercase();
+    [".tar", ".tgz", ".tbz2", ".txz", ".tzst"].iter().any(|ext| name.ends_with(ext))
+        || [".gz", ".bz2", ".xz", ".zst"].iter().any(|ext| name.strip_suffix(ext).is_some_and(|stem| stem.ends_with(".tar")))
+}
+
+fn build_globs(globs: &[String]) -> MyResult<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob).map_err(|_| format!("Invalid glob \"{}\"", glob))?);
+    }
+    Ok(builder.build()?)
+}
+
+fn parse_grep_colors(spec: &str) -> Colors {
+    let mut colors = Colors {
+        selected_match: Colour::Red.bold(),
+        context_match: Colour::Red.bold(),
+        filename: Style::new().fg(Colour::Purple),
+        line_number: Style::new().fg(Colour::Green),
+        byte_offset: Style::new().fg(Colour::Green),
+        separator: Style::new().fg(Colour::Cyan),
+    };
+    for (key, sgr) in spec.split(':').filter_map(|cap| cap.split_once('=')) {
+        let style = match parse_sgr(sgr) {
+            Some(style) => style,
+            None => continue,
+        };
+        match key {
+            "mt" => {
+                colors.selected_match = style;
+                colors.context_match = style;
+            }
+            "ms" => colors.selected_match = style,
+            "mc" => colors.context_match = style,
+            "fn" => colors.filename = style,
+            "ln" => colors.line_number = style,
+            "bn" => colors.byte_offset = style,
+            "se" => colors.separator = style,
+            _ => {}
+        }
+    }
+    colors
+}
+
+const COLOURS: [Colour; 8] = [Colour::Black, Colour::Red, Colour::Green, Colour::Yellow, Colour::Blue, Colour::Purple, Colour::Cyan, Colour::White];
+
+fn parse_sgr(sgr: &str) -> Option<Style> {
+    let codes = sgr.split(';').filter(|code| !code.is_empty()).map(|code| code.parse::<u8>().ok()).collect::<Option<Vec<_>>>()?;
+    let mut style = Style::new();
+    let mut codes = codes.into_iter();
+    while let Some(code) = codes.next() {
+        style = match code {
+            0 => Style::new(),
+            1 => style.bold(),
+            2 => style.dimmed(),
+            3 => style.italic(),
+            4 => style.underline(),
+            5 => style.blink(),
+            7 => style.reverse(),
+            8 => style.hidden(),
+            9 => style.strikethrough(),
+            30..=37 => style.fg(COLOURS[usize::from(code - 30)]),
+            38 | 48 => {
+                let colour = match codes.next()? {
+                    5 => Colour::Fixed(codes.next()?),
+                    2 => Colour::RGB(codes.next()?, codes.next()?, codes.next()?),
+                    _ => return None,
+                };
+                if code == 38 { style.fg(colour) } else { style.on(colour) }
+            }
+            40..=47 => style.on(COLOURS[usize::from(code - 40)]),
+            90..=97 => style.fg(Colour::Fixed(code - 90 + 8)),
+            100..=107 => style.on(Colour::Fixed(code - 100 + 8)),
+            _ => return None,
+        };
+    }
+    Some(style)
+}
+
+fn highlight(text: &[u8], pattern: &dyn Matcher, style: Style) -> Result<Vec<u8>, String> {
+    if style == Style::new() {
+        return Ok(text.to_vec());
+    }
+    let mut highlighted = vec![];
+    let mut last = 0;
+    for m in pattern.find_iter(trim_newline(text)) {
+        let m = m?;
+        if m.is_empty() {
+            continue;
+        }
+        highlighted.extend_from_slice(&text[last..m.start]);
+        highlighted.extend(paint(style, &text[m.clone()]));
+        last = m.end;
+    }
+    highlighted.extend_from_slice(&text[last..]);
+    Ok(highlighted)
+}
+
+fn replace(text: &[u8], pattern: &dyn Matcher, template: &[u8], style: Style) -> Result<Vec<u8>, String> {
+    let mut replaced = vec![];
+    let mut last = 0;
+    for (m, replacement) in pattern.replacements(trim_newline(text), template)? {
+        replaced.extend_from_slice(&text[last..m.start]);
+        replaced.extend(paint(style, &replacement));
+        last = m.end;
+    }
+    replaced.extend_from_slice(&text[last..]);
+    Ok(replaced)
+}
+
+fn replace_contents<'a>(contents: &'a [u8], pattern: &dyn Matcher, template: &[u8], max_count: Option<usize>) -> Result<(Vec<u8>, Vec<Change<'a>>), String> {
+    let mut edited = Vec::with_capacity(contents.len());
+    let mut changes = vec![];
+    for (i, line) in contents.split_inclusive(|&b| b == b'\n').enumerate() {
+        if max_count.is_none_or(|max| changes.len() < max) && pattern.is_match(trim_newline(line))? {
+            let replaced = replace(line, pattern, template, Style::new())?;
+            edited.extend_from_slice(&replaced);
+            changes.push(Change { number: i + 1, old: line, new: replaced });
+        } else {
+            edited.extend_from_slice(line);
+        }
+    }
+    Ok((edited, changes))
+}
+
+fn format_diff(filename: &str, changes: &[Change]) -> Vec<u8> {
+    let mut diff = format!("--- a/{}\n+++ b/{}\n", filename, filename).into_bytes();
+    let mut shift = 0isize;
+    for change in changes {
+        let (old_lines, new_lines) = (count_lines(change.old), count_lines(&change.new));
+        diff.extend(format!("@@ -{},{} +{},{} @@\n", change.number, old_lines, change.number as isize + shift, new_lines).bytes());
+        for (sign, text) in [('-', change.old), ('+', change.new.as_slice())] {
+            for line in text.split_inclusive(|&b| b == b'\n') {
+                diff.push(sign as u8);
+                diff.extend_from_slice(line);
+            }
+            if !text.ends_with(b"\n") {
+                diff.extend(b"\n\\ No newline at end of file\n");
+            }
+        }
+        shift += new_lines as isize - old_lines as isize;
+    }
+    diff
+}
+
+fn count_lines(text: &[u8]) -> usize {
+    text.split_inclusive(|&b| b == b'\n').count()
+}
+
+fn edit_file(config: &Config, filename: &str, out: &mut Output) -> MyResult<Searched> {
+    if filename == "-" {
+        return Err(From::from("cannot edit standard input in place"));
+    }
+    let contents = fs::read(filename)?;
+    if config.binary_files != BinaryFiles::Text && contents.iter().take(8192).any(|&b| b == 0) {
+        return Ok(Searched::default());
+    }
+    let template = config.replace.as_deref().unwrap_or_default();
+    let (edited, changes) = replace_contents(&contents, config.pattern.as_ref(), template, config.max_count)?;
+    let num_matches = changes.len();
+    if num_matches == 0 {
+        return Ok(Searched::default());
+    }
+    // The edited copy replaces the file it was made from, so a symlink is
+    // followed to its target rather than replaced, and a file with other
+    // hard links is left alone unless --break-links allows it to be split
+    // off from them.
+    let path = fs::canonicalize(filename)?;
+    let metadata = fs::metadata(&path)?;
+    let links = hard_links(&metadata);
+    if links > 1 && !config.break_links {
+        return Err(From::from(format!("has {} hard links, use --break-links to edit it anyway", links)));
+    }
+    if config.dry_run {
+        out.extend(format_diff(filename, &changes));
+        return Ok(Searched { num_matches, submatches: 0 });
     }
 
-    Ok(())
+    if let Some(suffix) = &config.backup {
+        fs::copy(&path, format!("{}{}", filename, suffix))?;
+    }
+    let mut temp = Builder::new().prefix(EDIT_PREFIX).tempfile_in(path.parent().unwrap_or(Path::new(".")))?;
+    temp.write_all(&edited)?;
+    temp.as_file().set_permissions(metadata.permissions())?;
+    temp.persist(&path)?;
+    Ok(Searched { num_matches, submatches: 0 })
 }
 
-fn find_files(paths: &[String], recursive: bool) -> Vec<MyResult<String>> {
-    let mut results = vec![];
+fn paint(style: Style, text: &[u8]) -> Vec<u8> {
+    let mut painted = style.prefix().to_string().into_bytes();
+    painted.extend_from_slice(text);
+    painted.extend(style.suffix().to_string().bytes());
+    painted
+}
+
+fn trim_newline(text: &[u8]) -> &[u8] {
+    text.strip_suffix(b"\n").unwrap_or(text)
+}
+
+fn parse_context(val: &str) -> MyResult<usize> {
+    val.parse().map_err(|_| From::from(format!("Invalid context length \"{}\"", val)))
+}
+
+impl WalkOptions {
+    fn is_selected(&self, path: &Path) -> bool {
+        let name = path.file_name().unwrap_or(path.as_os_str());
+        self.include.as_ref().is_none_or(|globs| globs.is_match(name)) && !self.exclude.as_ref().is_some_and(|globs| globs.is_match(name))
+    }
+}
+
+fn find_files<F: Fn(Result<String, String>) -> bool + Sync>(paths: &[String], options: &WalkOptions, visit: F) {
     for path in paths {
-        match path.as_str() {
-            "-" => results.push(Ok(path.to_string())),
+        let keep_going = match path.as_str() {
+            "-" => visit(Ok(path.to_string())),
             _ => match fs::metadata(path) {
                 Ok(metadata) => {
                     if metadata.is_dir() {
-                        if recursive {
-                            for entry in WalkDir::new(path).into_iter().flatten().filter(|e| e.file_type().is_file()) {
-                                results.push(Ok(entry.path().display().to_string()))
-                            }
+                        if options.recursive {
+                            walk_dir(path, options, &visit)
                         } else {
-                            results.push(Err(From::from(format!("{} is a directory", path))))
+                            visit(Err(format!("{} is a directory", path)))
                         }
-                    } else if metadata.is_file() {
-                        results.push(Ok(path.to_string()))
+                    } else if metadata.is_file() && options.is_selected(Path::new(path)) {
+                        visit(Ok(path.to_string()))
+                    } else {
+                        true
                     }
                 },
-                Err(e) => {
-                    results.push(Err(From::from(format!("{}: {}", path , e))))
-                }
+                Err(e) => visit(Err(format!("{}: {}", path, e)))
             }
+        };
+        if !keep_going {
+            break;
         }
     }
-    results
+}
+
+fn walk_dir<F: Fn(Result<String, String>) -> bool + Sync>(path: &str, options: &WalkOptions, visit: &F) -> bool {
+    let mut builder = WalkBuilder::new(path);
+    builder.standard_filters(!options.no_ignore).require_git(false).threads(options.threads);
+    let visit_entry = |entry: Result<ignore::DirEntry, ignore::Error>| match entry {
+        Err(e) => visit(Err(e.to_string())),
+        Ok(entry) if entry.file_name().to_string_lossy().starts_with(EDIT_PREFIX) => true,
+        Ok(entry) if entry.file_type().is_some_and(|t| t.is_file()) && options.is_selected(entry.path()) => visit(Ok(entry.path().display().to_string())),
+        Ok(_) => true,
+    };
+
+    if options.sort {
+        builder.sort_by_file_path(|a, b| a.cmp(b));
+        return builder.build().all(visit_entry);
+    }
+    let quit = AtomicBool::new(false);
+    builder.build_parallel().run(|| Box::new(|entry| {
+        if visit_entry(entry) {
+            WalkState::Continue
+        } else {
+            quit.store(true, Ordering::Relaxed);
+            WalkState::Quit
+        }
+    }));
+    !quit.load(Ordering::Relaxed)
+}
+
+// Empty files cannot be mapped, so they are returned as None.
+fn map_file(filename: &str) -> io::Result<Option<Mmap>> {
+    let file = File::open(filename)?;
+    if file.metadata()?.len() == 0 {
+        return Ok(None);
+    }
+    unsafe { Mmap::map(&file) }.map(Some)
+}
+
+impl Input<'_> {
+    fn is_binary(&mut self) -> io::Result<bool> {
+        Ok(match self {
+            Input::Stream(file) => file.fill_buf()?.contains(&0),
+            Input::Mapped(text) => text[..text.len().min(8192)].contains(&0),
+        })
+    }
+}
+
+fn scan<F: FnMut(Event) -> MyResult<()>>(input: Input, pattern: &dyn Matcher, invert_match: bool, context: &Context, max_count: Option<usize>, multiline: bool, emit: F) -> MyResult<usize> {
+    match input {
+        Input::Stream(mut file) if multiline => {
+            let mut text = vec![];
+            file.read_to_end(&mut text)?;
+            find_spans(&text, pattern, max_count, emit)
+        }
+        Input::Stream(file) => find_lines(file, pattern, invert_match, context, max_count, emit),
+        Input::Mapped(text) if multiline => find_spans(text, pattern, max_count, emit),
+        Input::Mapped(text) => find_lines(text, pattern, invert_match, context, max_count, emit),
+    }
 }
 
 fn open(filename: &str) -> MyResult<Box<dyn BufRead>> {
@@ -113,76 +1125,349 @@ fn open(filename: &str) -> MyResult<Box<dyn BufRead>> {
     }
 }
 
-fn find_lines<T: BufRead>(mut file: T, pattern: &Regex, invert_match: bool) -> MyResult<Vec<String>> {
-    let mut matches = vec![];
-    let mut line = String::new();
-    loop {
-        let bytes = file.read_line(&mut line)?;
+fn find_lines<T: BufRead, F: FnMut(Event) -> MyResult<()>>(mut file: T, pattern: &dyn Matcher, invert_match: bool, context: &Context, max_count: Option<usize>, mut emit: F) -> MyResult<usize> {
+    let mut num_matches = 0;
+    let mut before: VecDeque<(usize, usize, Vec<u8>)> = VecDeque::with_capacity(context.before);
+    let mut after_left = 0;
+    let mut last_emitted: Option<usize> = None;
+    let grouped = *context != Context::default();
+    let mut emit_at = |event: Event| {
+        if let Event::Match(line) | Event::Context(line) = &event {
+            if grouped && last_emitted.is_some_and(|last| last + 1 != line.number) {
+                emit(Event::Break)?;
+            }
+            last_emitted = Some(line.number);
+        }
+        emit(event)
+    };
+
+    let mut line = vec![];
+    let mut offset = 0;
+    for number in 1.. {
+        let done = max_count.is_some_and(|max| num_matches >= max);
+        if done && after_left == 0 {
+            break;
+        }
+        let bytes = file.read_until(b'\n', &mut line)?;
         if bytes == 0 {
             break;
         }
-        if pattern.is_match(&line) ^ invert_match {
-            matches.push(mem::take(&mut line));
+        if !done && pattern.is_match(trim_newline(&line))? ^ invert_match {
+            num_matches += 1;
+            for (number, offset, text) in before.drain(..) {
+                emit_at(Event::Context(Line { number, offset, text: &text }))?;
+            }
+            emit_at(Event::Match(Line { number, offset, text: &line }))?;
+            after_left = context.after;
+        } else if after_left > 0 {
+            emit_at(Event::Context(Line { number, offset, text: &line }))?;
+            after_left -= 1;
+        } else if context.before > 0 {
+            if before.len() == context.before {
+                before.pop_front();
+            }
+            before.push_back((number, offset, mem::take(&mut line)));
         }
+        offset += bytes;
         line.clear();
     }
-    Ok(matches)
+    Ok(num_matches)
+}
+
+fn find_spans<F: FnMut(Event) -> MyResult<()>>(text: &[u8], pattern: &dyn Matcher, max_count: Option<usize>, mut emit: F) -> MyResult<usize> {
+    let mut spans = pattern.find_iter(text).map(|m| m.map(|m| {
+        let start = text[..m.start].iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        let last = if m.is_empty() { m.start } else { m.end - 1 };
+        let end = text[last..].iter().position(|&b| b == b'\n').map_or(text.len(), |i| last + i + 1);
+        (start, end)
+    })).filter(|span| !span.as_ref().is_ok_and(|&(start, _)| start >= text.len())).peekable();
+
+    let mut num_matches = 0;
+    let (mut number, mut counted) = (1, 0);
+    while let Some(span) = spans.next() {
+        if max_count.is_some_and(|max| num_matches >= max) {
+            break;
+        }
+        let (start, mut end) = span?;
+        while let Some(Ok((_, next_end))) = spans.next_if(|span| span.as_ref().is_ok_and(|&(next, _)| next < end)) {
+            end = end.max(next_end);
+        }
+        number += text[counted..start].iter().filter(|&&b| b == b'\n').count();
+        counted = start;
+        num_matches += 1;
+        emit(Event::Match(Line { number, offset: start, text: &text[start..end] }))?;
+    }
+    Ok(num_matches)
 }
 
 #[cfg(test)]
 mod tests {
-    use std::io::Cursor;
+    use std::{fs::{self, File, OpenOptions}, io::{BufReader, Cursor, Read, Write}};
 
     use rand::{distributions::Alphanumeric, Rng};
-    use regex::{Regex, RegexBuilder};
 
-    use crate::{find_files, find_lines};
+    use ansi_term::{Colour, Style};
+
+    use std::sync::Mutex;
+
+    use crate::{build_globs, decompress, Change, Follower, find_files, find_lines, find_spans, format_diff, highlight, is_tar, replace, replace_contents, WalkOptions, matcher::{self, Matcher, MatcherOptions}, parse_grep_colors, parse_sgr, Context, Event};
+
+    fn matcher(pattern: &str, insensitive: bool) -> Box<dyn Matcher> {
+        matcher::new(&[pattern.to_string()], MatcherOptions { insensitive, ..Default::default() }).unwrap()
+    }
+
 
+    fn find_all(paths: &[&str], options: &WalkOptions) -> Vec<Result<String, String>> {
+        let found = Mutex::new(vec![]);
+        let paths = paths.iter().map(|path| path.to_string()).collect::<Vec<_>>();
+        find_files(&paths, options, |entry| {
+            found.lock().unwrap().push(entry.map(|path| path.replace('\\', "/")));
+            true
+        });
+        let mut found = found.into_inner().unwrap();
+        found.sort();
+        found
+    }
 
     #[test]
     fn test_find_files() {
-        let files = find_files(&["./tests/inputs/fox.txt".to_string()], false);
-        assert_eq!(files.len(), 1);
-        assert_eq!(files[0].as_ref().unwrap(), "./tests/inputs/fox.txt");
+        let flat = WalkOptions { threads: 2, ..Default::default() };
+        let recursive = WalkOptions { recursive: true, ..flat.clone() };
 
-        let files = find_files(&["./tests/inputs".to_string()], false);
-        assert_eq!(files.len(), 1);
-        if let Err(e) = &files[0] {
-            assert_eq!(e.to_string(), "./tests/inputs is a directory");
-        }
+        let files = find_all(&["./tests/inputs/fox.txt"], &flat);
+        assert_eq!(files, vec![Ok("./tests/inputs/fox.txt".to_string())]);
 
-        let res = find_files(&["./tests/inputs".to_string()], true);
-        let mut files = res.iter().map(|r| r.as_ref().unwrap().replace("\\", "/")).collect::<Vec<String>>();
-        files.sort();
-        assert_eq!(files.len(), 4);
-        assert_eq!(files, vec!["./tests/inputs/bustle.txt","./tests/inputs/empty.txt","./tests/inputs/fox.txt","./tests/inputs/nobody.txt"]);
+        let files = find_all(&["./tests/inputs"], &flat);
+        assert_eq!(files, vec![Err("./tests/inputs is a directory".to_string())]);
+
+        let files = find_all(&["./tests/inputs"], &recursive);
+        assert_eq!(files, vec![
+            Ok("./tests/inputs/bustle.txt".to_string()),
+            Ok("./tests/inputs/empty.txt".to_string()),
+            Ok("./tests/inputs/fox.txt".to_string()),
+            Ok("./tests/inputs/nobody.txt".to_string()),
+        ]);
 
         let bad = rand::thread_rng().sample_iter(&Alphanumeric).take(7).map(char::from).collect::<String>();
-        let files = find_files(&[bad], false);
+        let files = find_all(&[&bad], &flat);
         assert_eq!(files.len(), 1);
         assert!(files[0].is_err());
     }
 
+    #[test]
+    fn test_find_files_filters() {
+        let recursive = WalkOptions { recursive: true, threads: 2, ..Default::default() };
+        let files = find_all(&["tests/tree"], &recursive);
+        assert_eq!(files, vec![Ok("tests/tree/keep.txt".to_string()), Ok("tests/tree/sub/keep.rs".to_string())]);
+
+        let no_ignore = WalkOptions { no_ignore: true, ..recursive.clone() };
+        let files = find_all(&["tests/tree"], &no_ignore);
+        assert_eq!(files.len(), 5);
+
+        let include = WalkOptions { include: Some(build_globs(&["*.rs".to_string()]).unwrap()), ..recursive.clone() };
+        assert_eq!(find_all(&["tests/tree"], &include), vec![Ok("tests/tree/sub/keep.rs".to_string())]);
+
+        let exclude = WalkOptions { exclude: Some(build_globs(&["*.rs".to_string()]).unwrap()), sort: true, ..recursive };
+        assert_eq!(find_all(&["tests/tree", "tests/tree/sub/keep.rs"], &exclude), vec![Ok("tests/tree/keep.txt".to_string())]);
+    }
+
+    fn collect_lines(text: &[u8], pattern: &dyn Matcher, invert_match: bool, context: &Context) -> (usize, Vec<String>) {
+        collect_lines_max(text, pattern, invert_match, context, None)
+    }
+
+    fn collect_lines_max(text: &[u8], pattern: &dyn Matcher, invert_match: bool, context: &Context, max_count: Option<usize>) -> (usize, Vec<String>) {
+        let mut lines = vec![];
+        let num_matches = find_lines(Cursor::new(text), pattern, invert_match, context, max_count, |event| {
+            lines.push(match event {
+                Event::Match(line) => format!("{}:{}:{}", line.number, line.offset, String::from_utf8_lossy(line.text)),
+                Event::Context(line) => format!("{}-{}-{}", line.number, line.offset, String::from_utf8_lossy(line.text)),
+                Event::Break => "--".to_string(),
+            });
+            Ok(())
+        }).unwrap();
+        (num_matches, lines)
+    }
+
     #[test]
     fn test_find_lines() {
         let text = b"Lorem\nIpsum\r\nDOLOR";
-        let re1 = Regex::new("or").unwrap();
-        let re2 = RegexBuilder::new("or").case_insensitive(true).build().unwrap();
+        let re1 = matcher("or", false);
+        let re2 = matcher("or", true);
+        let none = Context::default();
+
+        assert_eq!(collect_lines(text, re1.as_ref(), false, &none), (1, vec!["1:0:Lorem\n".to_string()]));
+        assert_eq!(collect_lines(text, re1.as_ref(), true, &none), (2, vec!["2:6:Ipsum\r\n".to_string(), "3:13:DOLOR".to_string()]));
+        assert_eq!(collect_lines(text, re2.as_ref(), false, &none).0, 2);
+        assert_eq!(collect_lines(text, re2.as_ref(), true, &none).0, 1);
+
+        let (num_matches, lines) = collect_lines(b"caf\xe9\n\x00fox\n", matcher("fox", false).as_ref(), false, &none);
+        assert_eq!(num_matches, 1);
+        assert_eq!(lines, vec!["2:5:\0fox\n".to_string()]);
+    }
+
+    #[test]
+    fn test_find_lines_context() {
+        let text = b"a\nb\nmatch\nc\nd\ne\nf\nmatch\ng\nmatch\n";
+        let re = matcher("match", false);
 
-        let matches = find_lines(Cursor::new(&text), &re1, false);
-        assert!(matches.is_ok());
-        assert_eq!(matches.unwrap().len(), 1);
+        let (_, lines) = collect_lines(text, re.as_ref(), false, &Context { before: 1, after: 1 });
+        assert_eq!(lines, vec!["2-2-b\n", "3:4:match\n", "4-10-c\n", "--", "7-16-f\n", "8:18:match\n", "9-24-g\n", "10:26:match\n"]);
 
-        let matches = find_lines(Cursor::new(&text), &re1, true);
-        assert!(matches.is_ok());
-        assert_eq!(matches.unwrap().len(), 2);
+        let (_, lines) = collect_lines(text, re.as_ref(), false, &Context { before: 2, after: 0 });
+        assert_eq!(lines, vec!["1-0-a\n", "2-2-b\n", "3:4:match\n", "--", "6-14-e\n", "7-16-f\n", "8:18:match\n", "9-24-g\n", "10:26:match\n"]);
 
-        let matches = find_lines(Cursor::new(&text), &re2, false);
-        assert!(matches.is_ok());
-        assert_eq!(matches.unwrap().len(), 2);
+        let (_, lines) = collect_lines(text, re.as_ref(), false, &Context { before: 0, after: 3 });
+        assert_eq!(lines, vec!["3:4:match\n", "4-10-c\n", "5-12-d\n", "6-14-e\n", "--", "8:18:match\n", "9-24-g\n", "10:26:match\n"]);
 
-        let matches = find_lines(Cursor::new(&text), &re2, true);
-        assert!(matches.is_ok());
-        assert_eq!(matches.unwrap().len(), 1);
+        let (num_matches, lines) = collect_lines(text, re.as_ref(), false, &Context { before: 10, after: 10 });
+        assert_eq!(num_matches, 3);
+        assert_eq!(lines.len(), 10);
+        assert!(!lines.contains(&"--".to_string()));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_find_lines_max_count() {
+        let text = b"a\nmatch\nmatch\nb\nmatch\nc\n";
+        let re = matcher("match", false);
+        let none = Context::default();
+
+        assert_eq!(collect_lines_max(text, re.as_ref(), false, &none, Some(0)), (0, vec![]));
+        assert_eq!(collect_lines_max(text, re.as_ref(), false, &none, Some(1)), (1, vec!["2:2:match\n".to_string()]));
+        assert_eq!(collect_lines_max(text, re.as_ref(), true, &none, Some(2)).0, 2);
+
+        let (num_matches, lines) = collect_lines_max(text, re.as_ref(), false, &Context { before: 0, after: 2 }, Some(1));
+        assert_eq!(num_matches, 1);
+        assert_eq!(lines, vec!["2:2:match\n", "3-8-match\n", "4-14-b\n"]);
+    }
+
+    #[test]
+    fn test_decompress() {
+        for filename in ["tests/zip/fox.txt.gz", "tests/zip/fox.txt.bz2", "tests/zip/fox.txt.xz", "tests/zip/fox.txt.zst", "tests/inputs/fox.txt"] {
+            let mut text = String::new();
+            decompress(Box::new(BufReader::new(File::open(filename).unwrap()))).unwrap().read_to_string(&mut text).unwrap();
+            assert_eq!(text, "The quick brown fox jumps over the lazy dog.\n", "{}", filename);
+        }
+    }
+
+    #[test]
+    fn test_is_tar() {
+        assert!(is_tar("logs.tar"));
+        assert!(is_tar("logs.tar.gz"));
+        assert!(is_tar("LOGS.TGZ"));
+        assert!(is_tar("dir/logs.tar.zst"));
+        assert!(!is_tar("logs.gz"));
+        assert!(!is_tar("tar.gz"));
+        assert!(!is_tar("logs.tar.txt"));
+    }
+
+    #[test]
+    fn test_replace() {
+        let re = matcher(r"(\w+)@(\w+)", false);
+        assert_eq!(replace(b"mail a@b now\n", re.as_ref(), b"$2.$1", Style::new()).unwrap(), b"mail b.a now\n");
+        assert_eq!(replace(b"x@y\n", re.as_ref(), b"$1", Colour::Red.bold()).unwrap(), b"\x1b[1;31mx\x1b[0m\n");
+        assert_eq!(replace(b"none\n", re.as_ref(), b"$1", Style::new()).unwrap(), b"none\n");
+    }
+
+    #[test]
+    fn test_replace_contents() {
+        let re = matcher("fox", false);
+        let (edited, changes) = replace_contents(b"a fox\nno\nfox fox", re.as_ref(), b"cat", None).unwrap();
+        assert_eq!(edited, b"a cat\nno\ncat cat");
+        assert_eq!(changes, vec![
+            Change { number: 1, old: b"a fox\n", new: b"a cat\n".to_vec() },
+            Change { number: 3, old: b"fox fox", new: b"cat cat".to_vec() },
+        ]);
+
+        let (edited, changes) = replace_contents(b"fox\nfox\n", re.as_ref(), b"cat", Some(1)).unwrap();
+        assert_eq!(edited, b"cat\nfox\n");
+        assert_eq!(changes.len(), 1);
+
+        let diff = format_diff("f.txt", &replace_contents(b"fox\nb\nfox", re.as_ref(), b"c\nd", None).unwrap().1);
+        assert_eq!(String::from_utf8(diff).unwrap(), "--- a/f.txt\n+++ b/f.txt\n@@ -1,1 +1,2 @@\n-fox\n+c\n+d\n@@ -3,1 +4,2 @@\n-fox\n\\ No newline at end of file\n+c\n+d\n\\ No newline at end of file\n");
+    }
+
+    #[test]
+    fn test_find_spans() {
+        let text = b"a {\n  b\n}\nc {\n}\nd";
+        let collect = |pattern: &str, max_count| {
+            let mut lines = vec![];
+            let num_matches = find_spans(text, matcher(pattern, false).as_ref(), max_count, |event| {
+                if let Event::Match(line) = event {
+                    lines.push(format!("{}:{}:{}", line.number, line.offset, String::from_utf8_lossy(line.text)));
+                }
+                Ok(())
+            }).unwrap();
+            (num_matches, lines)
+        };
+
+        assert_eq!(collect(r"\{\n\s*b", None), (1, vec!["1:0:a {\n  b\n".to_string()]));
+        assert_eq!(collect(r"\{\n[^}]*\}", None), (2, vec!["1:0:a {\n  b\n}\n".to_string(), "4:10:c {\n}\n".to_string()]));
+        assert_eq!(collect(r"\{\n[^}]*\}", Some(1)).0, 1);
+        assert_eq!(collect(r"\{\n\s+|b\n\}", None), (1, vec!["1:0:a {\n  b\n}\n".to_string()]));
+        assert_eq!(collect(r"b|\}\nc", None), (2, vec!["2:4:  b\n".to_string(), "3:8:}\nc {\n".to_string()]));
+        assert_eq!(collect("(?m)^d$", None), (1, vec!["6:16:d".to_string()]));
+        assert_eq!(collect("x", None), (0, vec![]));
+    }
+
+    #[test]
+    fn test_follower() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        let name = path.to_string_lossy().to_string();
+        let append = |text: &str| OpenOptions::new().append(true).create(true).open(&path).unwrap().write_all(text.as_bytes()).unwrap();
+
+        append("a\nb\npart");
+        let mut uncounted = Follower::open(&name, false).unwrap();
+        let mut follower = Follower::open(&name, true).unwrap();
+        assert_eq!(follower.poll().unwrap(), vec![]);
+
+        append("ial\nc\n");
+        assert_eq!(follower.poll().unwrap(), vec![(3, 4, b"partial\n".to_vec()), (4, 12, b"c\n".to_vec())]);
+        assert_eq!(uncounted.poll().unwrap(), vec![(0, 4, b"partial\n".to_vec()), (0, 12, b"c\n".to_vec())]);
+
+        fs::write(&path, "x\n").unwrap();
+        assert_eq!(follower.poll().unwrap(), vec![(1, 0, b"x\n".to_vec())]);
+
+        if cfg!(unix) {
+            fs::rename(&path, dir.path().join("app.log.1")).unwrap();
+            OpenOptions::new().append(true).open(dir.path().join("app.log.1")).unwrap().write_all(b"y\n").unwrap();
+            append("z\n");
+            assert_eq!(follower.poll().unwrap(), vec![(2, 2, b"y\n".to_vec()), (1, 0, b"z\n".to_vec())]);
+        }
+    }
+
+    #[test]
+    fn test_parse_sgr() {
+        assert_eq!(parse_sgr(""), Some(Style::new()));
+        assert_eq!(parse_sgr("01;31"), Some(Colour::Red.bold()));
+        assert_eq!(parse_sgr("7;43"), Some(Style::new().reverse().on(Colour::Yellow)));
+        assert_eq!(parse_sgr("4;38;5;208"), Some(Style::new().underline().fg(Colour::Fixed(208))));
+        assert_eq!(parse_sgr("48;2;1;2;3"), Some(Style::new().on(Colour::RGB(1, 2, 3))));
+        assert_eq!(parse_sgr("95;100"), Some(Style::new().fg(Colour::Fixed(13)).on(Colour::Fixed(8))));
+        assert_eq!(parse_sgr("38;9"), None);
+        assert_eq!(parse_sgr("red"), None);
+    }
+
+    #[test]
+    fn test_parse_grep_colors() {
+        let colors = parse_grep_colors("");
+        assert_eq!(colors.selected_match, Colour::Red.bold());
+        assert_eq!(colors.separator, Style::new().fg(Colour::Cyan));
+
+        let colors = parse_grep_colors("mt=01;32:fn=:ln=bogus:ne");
+        assert_eq!(colors.selected_match, Colour::Green.bold());
+        assert_eq!(colors.context_match, Colour::Green.bold());
+        assert_eq!(colors.filename, Style::new());
+        assert_eq!(colors.line_number, Style::new().fg(Colour::Green));
+    }
+
+    #[test]
+    fn test_highlight() {
+        let re = matcher("o", false);
+        assert_eq!(highlight(b"foo\n", re.as_ref(), Style::new()).unwrap(), b"foo\n");
+        assert_eq!(highlight(b"foo\n", re.as_ref(), Colour::Red.bold()).unwrap(), b"f\x1b[1;31mo\x1b[0m\x1b[1;31mo\x1b[0m\n");
+        assert_eq!(highlight(b"bar\n", re.as_ref(), Colour::Red.bold()).unwrap(), b"bar\n");
+        assert_eq!(highlight(b"b\xe9r\n", matcher("r", false).as_ref(), Colour::Red.bold()).unwrap(), b"b\xe9\x1b[1;31mr\x1b[0m\n");
+        assert_eq!(highlight(b"bar\n", matcher("", false).as_ref(), Colour::Red.bold()).unwrap(), b"bar\n");
+    }
+}
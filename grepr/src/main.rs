@@ -1,6 +1,11 @@
 fn main() {
-    if let Err(e) = grepr::get_args().and_then(grepr::run) {
-        eprintln!("{}", e);
-        std::process::exit(1);
+    match grepr::get_args().and_then(grepr::run) {
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+        Ok(grepr::Outcome::Failed) => std::process::exit(2),
+        Ok(grepr::Outcome::NoMatch) => std::process::exit(1),
+        Ok(grepr::Outcome::Matched) => {}
     }
 }
@@ -0,0 +1,334 @@
+use std::{borrow::Cow, fmt::Debug, ops::Range};
+
+use aho_corasick::{AhoCorasick, MatchKind};
+use regex::bytes::{Regex, RegexBuilder};
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MatcherOptions {
+    pub fixed_strings: bool,
+    pub insensitive: bool,
+    pub word: bool,
+    pub line: bool,
+    pub perl: bool,
+    pub multiline: bool
+}
+
+// Each match with the template expanded for it.
+pub type Replacements = Vec<(Range<usize>, Vec<u8>)>;
+
+// Searches fail when a -P pattern gives up on a line, e.g. once it has
+// backtracked too much, so every method returns the error for the caller to
+// report rather than quietly missing matches.
+pub trait Matcher: Debug + Sync {
+    fn find_iter<'a>(&'a self, text: &'a [u8]) -> Box<dyn Iterator<Item = Result<Range<usize>, String>> + 'a>;
+
+    fn replacements(&self, text: &[u8], template: &[u8]) -> Result<Replacements, String>;
+
+    fn is_match(&self, text: &[u8]) -> Result<bool, String> {
+        Ok(self.find(text)?.is_some())
+    }
+
+    fn find(&self, text: &[u8]) -> Result<Option<Range<usize>>, String> {
+        self.find_iter(text).next().transpose()
+    }
+}
+
+#[derive(Debug)]
+struct RegexMatcher(Regex);
+
+#[derive(Debug)]
+struct FixedMatcher(AhoCorasick);
+
+#[derive(Debug)]
+struct FancyMatcher(fancy_regex::Regex);
+
+pub fn new(patterns: &[String], options: MatcherOptions) -> Result<Box<dyn Matcher>, String> {
+    // An empty pattern list matches nothing, whichever engine was asked for.
+    if patterns.is_empty() || (!options.perl && options.fixed_strings && !options.insensitive && !options.word && !options.line) {
+        return AhoCorasick::builder()
+            .match_kind(MatchKind::LeftmostLongest)
+            .build(patterns)
+            .map(|ac| Box::new(FixedMatcher(ac)) as Box<dyn Matcher>)
+            .map_err(|e| e.to_string());
+    }
+
+    let alternation = patterns.iter()
+        .map(|pattern| format!("(?:{})", if options.fixed_strings { regex::escape(pattern) } else { pattern.to_string() }))
+        .collect::<Vec<_>>()
+        .join("|");
+    let combined = if options.line {
+        format!("^(?:{})$", alternation)
+    } else if options.word {
+        format!(r"\b(?:{})\b", alternation)
+    } else {
+        alternation
+    };
+    if options.perl {
+        let combined = if options.multiline { format!("(?m){}", combined) } else { combined };
+        return fancy_regex::RegexBuilder::new(&combined)
+            .case_insensitive(options.insensitive)
+            .build()
+            .map(|re| Box::new(FancyMatcher(re)) as Box<dyn Matcher>)
+            .map_err(|_| {
+                let bad = patterns.iter().find(|pattern| fancy_regex::Regex::new(pattern).is_err()).unwrap_or(&combined);
+                format!("Invalid pattern \"{}\"", bad)
+            });
+    }
+    RegexBuilder::new(&combined)
+        .case_insensitive(options.insensitive)
+        .multi_line(options.multiline)
+        .size_limit(1 << 30)
+        .dfa_size_limit(1 << 30)
+        .build()
+        .map(|re| Box::new(RegexMatcher(re)) as Box<dyn Matcher>)
+        .map_err(|_| {
+            let bad = patterns.iter().find(|pattern| Regex::new(pattern).is_err()).unwrap_or(&combined);
+            if fancy_regex::Regex::new(bad).is_ok() {
+                format!("Invalid pattern \"{}\" (lookaround and backreferences require -P)", bad)
+            } else {
+                format!("Invalid pattern \"{}\"", bad)
+            }
+        })
+}
+
+impl Matcher for RegexMatcher {
+    fn find_iter<'a>(&'a self, text: &'a [u8]) -> Box<dyn Iterator<Item = Result<Range<usize>, String>> + 'a> {
+        Box::new(self.0.find_iter(text).map(|m| Ok(m.range())))
+    }
+
+    fn replacements(&self, text: &[u8], template: &[u8]) -> Result<Replacements, String> {
+        Ok(self.0.captures_iter(text).map(|caps| {
+            let mut replacement = vec![];
+            caps.expand(template, &mut replacement);
+            (caps.get(0).unwrap().range(), replacement)
+        }).collect())
+    }
+
+    fn is_match(&self, text: &[u8]) -> Result<bool, String> {
+        Ok(self.0.is_match(text))
+    }
+}
+
+impl Matcher for FixedMatcher {
+    fn find_iter<'a>(&'a self, text: &'a [u8]) -> Box<dyn Iterator<Item = Result<Range<usize>, String>> + 'a> {
+        Box::new(self.0.find_iter(text).map(|m| Ok(m.range())))
+    }
+
+    fn replacements(&self, text: &[u8], template: &[u8]) -> Result<Replacements, String> {
+        Ok(self.0.find_iter(text).map(|m| (m.range(), template.to_vec())).collect())
+    }
+
+    fn is_match(&self, text: &[u8]) -> Result<bool, String> {
+        Ok(self.0.is_match(text))
+    }
+}
+
+// fancy-regex only searches &str, so invalid UTF-8 is replaced with U+FFFD
+// and the line is searched whole, keeping anchors and lookaround right.
+// `Offsets` maps positions in the decoded line back to byte offsets as
+// (decoded, original) pairs at the start of every run; it is empty for valid
+// UTF-8, where the two are the same.
+type Offsets = Vec<(usize, usize)>;
+
+impl FancyMatcher {
+    fn decode(text: &[u8]) -> (Cow<'_, str>, Offsets) {
+        if let Ok(valid) = std::str::from_utf8(text) {
+            return (Cow::Borrowed(valid), vec![]);
+        }
+        let (mut decoded, mut offsets, mut original) = (String::new(), vec![], 0);
+        for chunk in text.utf8_chunks() {
+            offsets.push((decoded.len(), original));
+            decoded.push_str(chunk.valid());
+            original += chunk.valid().len();
+            if !chunk.invalid().is_empty() {
+                offsets.push((decoded.len(), original));
+                decoded.push(char::REPLACEMENT_CHARACTER);
+                original += chunk.invalid().len();
+            }
+        }
+        offsets.push((decoded.len(), original));
+        (Cow::Owned(decoded), offsets)
+    }
+
+    fn offset(offsets: &[(usize, usize)], pos: usize) -> usize {
+        match offsets.binary_search_by_key(&pos, |&(decoded, _)| decoded) {
+            Ok(i) => offsets[i].1,
+            Err(0) => pos,
+            Err(i) => offsets[i - 1].1 + pos - offsets[i - 1].0,
+        }
+    }
+
+    fn range(offsets: &[(usize, usize)], m: fancy_regex::Match) -> Range<usize> {
+        FancyMatcher::offset(offsets, m.start())..FancyMatcher::offset(offsets, m.end())
+    }
+
+    // Expands $1, $name, ${name} and $$ like regex's Captures::expand, but
+    // copies the groups from the original bytes so invalid UTF-8 survives.
+    fn expand(caps: &fancy_regex::Captures, text: &[u8], offsets: &[(usize, usize)], template: &[u8]) -> Vec<u8> {
+        let mut expanded = vec![];
+        let mut rest = template;
+        while let Some(i) = rest.iter().position(|&b| b == b'$') {
+            expanded.extend_from_slice(&rest[..i]);
+            rest = &rest[i + 1..];
+            let (name, len) = match rest.first() {
+                Some(b'$') => (None, 1),
+                Some(b'{') => match rest.iter().position(|&b| b == b'}') {
+                    Some(end) => (Some(&rest[1..end]), end + 1),
+                    None => (None, 0),
+                },
+                _ => {
+                    let len = rest.iter().take_while(|&&b| b == b'_' || b.is_ascii_alphanumeric()).count();
+                    ((len > 0).then(|| &rest[..len]), len)
+                }
+            };
+            rest = &rest[len..];
+            let name = match name {
+                Some(name) => String::from_utf8_lossy(name),
+                None => {
+                    expanded.push(b'$');
+                    continue;
+                }
+            };
+            let group = match name.parse::<usize>() {
+                Ok(i) => caps.get(i),
+                Err(_) => caps.name(&name),
+            };
+            if let Some(m) = group {
+                expanded.extend_from_slice(&text[FancyMatcher::range(offsets, m)]);
+            }
+        }
+        expanded.extend_from_slice(rest);
+        expanded
+    }
+}
+
+impl Matcher for FancyMatcher {
+    // The decoded line cannot outlive this call, so the matches are found up
+    // front, stopping at the first error.
+    fn find_iter<'a>(&'a self, text: &'a [u8]) -> Box<dyn Iterator<Item = Result<Range<usize>, String>> + 'a> {
+        let (decoded, offsets) = FancyMatcher::decode(text);
+        let mut found = vec![];
+        for m in self.0.find_iter(&decoded) {
+            let failed = m.is_err();
+            found.push(m.map(|m| FancyMatcher::range(&offsets, m)).map_err(|e| e.to_string()));
+            if failed {
+                break;
+            }
+        }
+        Box::new(found.into_iter())
+    }
+
+    fn replacements(&self, text: &[u8], template: &[u8]) -> Result<Replacements, String> {
+        let (decoded, offsets) = FancyMatcher::decode(text);
+        self.0.captures_iter(&decoded).map(|caps| {
+            let caps = caps.map_err(|e| e.to_string())?;
+            Ok((FancyMatcher::range(&offsets, caps.get(0).unwrap()), FancyMatcher::expand(&caps, text, &offsets, template)))
+        }).collect()
+    }
+
+    fn is_match(&self, text: &[u8]) -> Result<bool, String> {
+        self.0.is_match(&FancyMatcher::decode(text).0).map_err(|e| e.to_string())
+    }
+
+    fn find(&self, text: &[u8]) -> Result<Option<Range<usize>>, String> {
+        let (decoded, offsets) = FancyMatcher::decode(text);
+        Ok(self.0.find(&decoded).map_err(|e| e.to_string())?.map(|m| FancyMatcher::range(&offsets, m)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{new, MatcherOptions};
+
+    fn patterns(vals: &[&str]) -> Vec<String> {
+        vals.iter().map(|val| val.to_string()).collect()
+    }
+
+    #[test]
+    fn test_matcher_regex() {
+        let matcher = new(&patterns(&["fo+", "ba[rz]"]), MatcherOptions::default()).unwrap();
+        assert!(format!("{:?}", matcher).starts_with("RegexMatcher"));
+        assert!(matcher.is_match(b"foo").unwrap());
+        assert!(matcher.is_match(b"baz").unwrap());
+        assert!(!matcher.is_match(b"bay").unwrap());
+        assert_eq!(matcher.find_iter(b"a foo bar").collect::<Result<Vec<_>, _>>().unwrap(), vec![2..5, 6..9]);
+
+        let res = new(&patterns(&["ok", "*foo"]), MatcherOptions::default());
+        assert_eq!(res.unwrap_err(), "Invalid pattern \"*foo\"");
+    }
+
+    #[test]
+    fn test_matcher_fixed() {
+        let fixed = MatcherOptions { fixed_strings: true, ..Default::default() };
+        let matcher = new(&patterns(&["a.c", "a.cd"]), fixed).unwrap();
+        assert!(format!("{:?}", matcher).starts_with("FixedMatcher"));
+        assert!(!matcher.is_match(b"abc").unwrap());
+        assert_eq!(matcher.find(b"xa.cd").unwrap(), Some(1..5));
+
+        let matcher = new(&patterns(&["A.C"]), MatcherOptions { insensitive: true, ..fixed }).unwrap();
+        assert!(matcher.is_match(b"xa.c").unwrap());
+        assert!(!matcher.is_match(b"xabc").unwrap());
+
+        let matcher = new(&[], MatcherOptions::default()).unwrap();
+        assert!(!matcher.is_match(b"anything").unwrap());
+        assert!(!matcher.is_match(b"").unwrap());
+    }
+
+    #[test]
+    fn test_matcher_word_line() {
+        let word = MatcherOptions { word: true, ..Default::default() };
+        let matcher = new(&patterns(&["the", "fox"]), word).unwrap();
+        assert!(matcher.is_match(b"over the lazy").unwrap());
+        assert!(!matcher.is_match(b"there").unwrap());
+        assert!(!matcher.is_match(b"foxes").unwrap());
+
+        let line = MatcherOptions { line: true, fixed_strings: true, ..Default::default() };
+        let matcher = new(&patterns(&["a.b", "c"]), line).unwrap();
+        assert!(matcher.is_match(b"a.b").unwrap());
+        assert!(matcher.is_match(b"c").unwrap());
+        assert!(!matcher.is_match(b"a.b c").unwrap());
+        assert!(!matcher.is_match(b"axb").unwrap());
+    }
+
+    #[test]
+    fn test_matcher_replacements() {
+        let matcher = new(&patterns(&[r"(\w+)@(?P<host>\w+)"]), MatcherOptions::default()).unwrap();
+        assert_eq!(matcher.replacements(b"a@b, c@d", b"${host}:$1").unwrap(), vec![(0..3, b"b:a".to_vec()), (5..8, b"d:c".to_vec())]);
+
+        let fixed = MatcherOptions { fixed_strings: true, ..Default::default() };
+        let matcher = new(&patterns(&["$1"]), fixed).unwrap();
+        assert_eq!(matcher.replacements(b"x$1y", b"$0").unwrap(), vec![(1..3, b"$0".to_vec())]);
+    }
+
+    #[test]
+    fn test_matcher_perl() {
+        let res = new(&patterns(&[r"foo(?=bar)"]), MatcherOptions::default());
+        assert_eq!(res.unwrap_err(), "Invalid pattern \"foo(?=bar)\" (lookaround and backreferences require -P)");
+
+        let perl = MatcherOptions { perl: true, ..Default::default() };
+        let matcher = new(&patterns(&[r"foo(?=bar)"]), perl).unwrap();
+        assert!(format!("{:?}", matcher).starts_with("FancyMatcher"));
+        assert_eq!(matcher.find(b"foobaz foobar").unwrap(), Some(7..10));
+
+        let matcher = new(&patterns(&[r"(\w)\1"]), perl).unwrap();
+        assert_eq!(matcher.find_iter(b"caf\xe9 ab bb").collect::<Result<Vec<_>, _>>().unwrap(), vec![8..10]);
+        assert_eq!(matcher.replacements(b"\xffxx", b"<$1>").unwrap(), vec![(1..3, b"<x>".to_vec())]);
+        assert_eq!(new(&patterns(&[r"(.)\1"]), perl).unwrap().replacements(b"\xe9\xe9 xx", b"$$${1}$0").unwrap(), vec![(0..2, b"$\xe9\xe9\xe9".to_vec()), (3..5, b"$xxx".to_vec())]);
+        assert!(!new(&patterns(&["^ bar"]), perl).unwrap().is_match(b"caf\xe9 bar").unwrap());
+        assert!(!new(&patterns(&["foo"]), MatcherOptions { line: true, ..perl }).unwrap().is_match(b"\xe9foo").unwrap());
+        assert_eq!(new(&patterns(&[r"(?<=\xff\s)bar"]), perl).unwrap().find(b"\xff bar").unwrap(), None);
+        assert_eq!(new(&patterns(&[r"(?<=\x{FFFD}\s)bar"]), perl).unwrap().find(b"\xff bar").unwrap(), Some(2..5));
+
+        let matcher = new(&patterns(&[r"(a|aa)*\1c|b$"]), perl).unwrap();
+        let text = format!("{}b", "a".repeat(30));
+        assert!(matcher.find(text.as_bytes()).is_err());
+        assert!(matcher.replacements(text.as_bytes(), b"x").is_err());
+
+        assert!(!new(&[], perl).unwrap().is_match(b"anything").unwrap());
+
+        let fixed = MatcherOptions { fixed_strings: true, ..perl };
+        assert!(new(&patterns(&["a.c"]), fixed).unwrap().is_match(b"a.c").unwrap());
+        assert!(new(&patterns(&["(?<=x"]), perl).is_err());
+        assert!(new(&patterns(&["THE"]), MatcherOptions { insensitive: true, ..perl }).unwrap().is_match(b"the").unwrap());
+    }
+}
@@ -1,7 +1,9 @@
+#![allow(clippy::needless_borrows_for_generic_args)]
+
 use assert_cmd::Command;
 use predicates::prelude::*;
 use rand::{distributions::Alphanumeric, Rng};
-use std::{fs, path::Path};
+use std::{fs, io::Write, path::Path};
 use sys_info::os_type;
 
 type TestResult = Result<(), Box<dyn std::error::Error>>;
@@ -12,6 +14,7 @@ const EMPTY: &str = "tests/inputs/empty.txt";
 const FOX: &str = "tests/inputs/fox.txt";
 const NOBODY: &str = "tests/inputs/nobody.txt";
 const INPUTS_DIR: &str = "tests/inputs";
+const BINARY: &str = "tests/binary.dat";
 
 // --------------------------------------------------
 fn gen_bad_file() -> String {
@@ -38,6 +41,21 @@ fn dies_no_args() -> TestResult {
     Ok(())
 }
 
+// --------------------------------------------------
+#[test]
+fn dies_bad_flag() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--bogus", "x", FOX])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("--bogus"));
+    Command::cargo_bin(PRG)?
+        .arg("--help")
+        .assert()
+        .success();
+    Ok(())
+}
+
 // --------------------------------------------------
 #[test]
 fn dies_bad_pattern() -> TestResult {
@@ -72,7 +90,7 @@ fn run(args: &[&str], expected_file: &str) -> TestResult {
         expected_file
     };
 
-    let expected = fs::read_to_string(&expected_file)?;
+    let expected = fs::read(expected_file)?;
 
     Command::cargo_bin(PRG)?
         .args(args)
@@ -277,3 +295,595 @@ fn stdin_insensitive_count() -> TestResult {
         .stdout(expected);
     Ok(())
 }
+
+// --------------------------------------------------
+#[test]
+fn stdin_streams_matches() -> TestResult {
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin(PRG))
+        .arg("foo")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let mut line = String::new();
+        std::io::BufRead::read_line(&mut std::io::BufReader::new(stdout), &mut line).ok();
+        tx.send(line).ok();
+    });
+    stdin.write_all(b"foo\nbar\n")?;
+
+    // The first match has to arrive while stdin is still open.
+    let first = rx.recv_timeout(std::time::Duration::from_secs(10));
+    drop(stdin);
+    child.wait()?;
+    assert_eq!(first?, "foo\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_context() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-C", "x", "fox", FOX])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Invalid context length \"x\""));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn after_context_multiple_files() -> TestResult {
+    run(&["-A", "1", "The", BUSTLE, FOX], "tests/expected/all.the.after1")
+}
+
+// --------------------------------------------------
+#[test]
+fn before_context() -> TestResult {
+    run(&["-B", "2", "death", BUSTLE], "tests/expected/bustle.txt.death.before2")
+}
+
+// --------------------------------------------------
+#[test]
+fn context_insensitive() -> TestResult {
+    run(&["-C", "1", "-i", "nobody", NOBODY], "tests/expected/nobody.txt.context1")
+}
+
+// --------------------------------------------------
+#[test]
+fn line_number() -> TestResult {
+    run(&["-n", "The", BUSTLE], "tests/expected/bustle.txt.the.capitalized.line_number")
+}
+
+// --------------------------------------------------
+#[test]
+fn byte_offset_only_matching() -> TestResult {
+    run(
+        &["-b", "-o", "the", BUSTLE],
+        "tests/expected/bustle.txt.the.lowercase.byte_offset.only_matching",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn column() -> TestResult {
+    run(&["-n", "--column", "the", BUSTLE], "tests/expected/bustle.txt.the.lowercase.column")
+}
+
+// --------------------------------------------------
+#[test]
+fn with_filename() -> TestResult {
+    run(&["-H", "-n", "fox", FOX], "tests/expected/fox.txt.fox.with_filename")
+}
+
+// --------------------------------------------------
+#[test]
+fn no_filename() -> TestResult {
+    run(&["-h", "The", BUSTLE, FOX], "tests/expected/all.the.capitalized.no_filename")
+}
+
+// --------------------------------------------------
+#[test]
+fn color_always() -> TestResult {
+    run(
+        &["--color=always", "-n", "-C1", "death", BUSTLE, FOX],
+        "tests/expected/all.death.color",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn color_auto_not_tty() -> TestResult {
+    run(&["--color=auto", "The", BUSTLE], "tests/expected/bustle.txt.the.capitalized")
+}
+
+// --------------------------------------------------
+#[test]
+fn color_never() -> TestResult {
+    run(&["--color=never", "The", BUSTLE], "tests/expected/bustle.txt.the.capitalized")
+}
+
+// --------------------------------------------------
+#[test]
+fn color_grep_colors() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--color=always", "fox", FOX])
+        .env("GREP_COLORS", "ms=04;32")
+        .assert()
+        .success()
+        .stdout("The quick brown \x1b[4;32mfox\x1b[0m jumps over the lazy dog.\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_pattern_file() -> TestResult {
+    let bad = gen_bad_file();
+    let expected = format!("{}: .* [(]os error 2[)]", bad);
+    Command::cargo_bin(PRG)?
+        .args(["-f", &bad, FOX])
+        .assert()
+        .failure()
+        .stderr(predicate::str::is_match(expected)?);
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_pattern_multiple() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-e", "fox", "-e", "*foo", FOX])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Invalid pattern \"*foo\""));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn multiple_patterns() -> TestResult {
+    run(&["-e", "death", "-e", "love", BUSTLE, FOX], "tests/expected/all.death.love")
+}
+
+// --------------------------------------------------
+#[test]
+fn pattern_file() -> TestResult {
+    run(&["-f", "tests/patterns.txt", BUSTLE], "tests/expected/bustle.txt.patterns")
+}
+
+// --------------------------------------------------
+#[test]
+fn fixed_strings() -> TestResult {
+    run(&["-F", "-o", "e.", FOX], "tests/expected/fox.txt.fixed.only_matching")
+}
+
+// --------------------------------------------------
+#[test]
+fn word_regexp() -> TestResult {
+    run(&["-w", "the", BUSTLE, FOX], "tests/expected/all.the.word")
+}
+
+// --------------------------------------------------
+#[test]
+fn line_regexp() -> TestResult {
+    run(&["-x", "-i", "until.eternity.", BUSTLE], "tests/expected/bustle.txt.line")
+}
+
+// --------------------------------------------------
+#[test]
+fn files_with_matches() -> TestResult {
+    run(&["-l", "The", BUSTLE, FOX, NOBODY], "tests/expected/all.the.files_with_matches")
+}
+
+// --------------------------------------------------
+#[test]
+fn files_without_match() -> TestResult {
+    run(&["-L", "dog", BUSTLE, FOX, NOBODY], "tests/expected/all.dog.files_without_match")
+}
+
+// --------------------------------------------------
+#[test]
+fn max_count_after_context() -> TestResult {
+    run(&["-m", "1", "-A", "2", "The", BUSTLE], "tests/expected/bustle.txt.the.max_count1.after2")
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_max_count() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-m", "x", "fox", FOX])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Invalid max count \"x\""));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn quiet_match() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-q", "fox", FOX, BUSTLE])
+        .assert()
+        .success()
+        .stdout("");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn quiet_no_match() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-q", "fox", BUSTLE])
+        .assert()
+        .code(1)
+        .stdout("");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn bad_file_exit_code() -> TestResult {
+    let bad = gen_bad_file();
+    Command::cargo_bin(PRG)?
+        .args(["foo", &bad])
+        .assert()
+        .code(2);
+    Command::cargo_bin(PRG)?
+        .args(["The", FOX, &bad])
+        .assert()
+        .code(2)
+        .stdout(predicate::str::contains("The quick brown fox"));
+    Command::cargo_bin(PRG)?
+        .args(["-q", "The", FOX, &bad])
+        .assert()
+        .success();
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn recursive_ignore() -> TestResult {
+    run(&["-r", "--sort=path", "dog", "tests/tree"], "tests/expected/tree.dog")
+}
+
+// --------------------------------------------------
+#[test]
+fn recursive_no_ignore() -> TestResult {
+    run(&["-r", "--sort=path", "--no-ignore", "dog", "tests/tree"], "tests/expected/tree.dog.no_ignore")
+}
+
+// --------------------------------------------------
+#[test]
+fn recursive_include() -> TestResult {
+    run(&["-r", "--sort=path", "--include", "*.rs", "dog", "tests/tree"], "tests/expected/tree.dog.include")
+}
+
+// --------------------------------------------------
+#[test]
+fn recursive_exclude() -> TestResult {
+    run(&["-r", "--sort=path", "--exclude", "*.rs", "-j", "1", "dog", "tests/tree"], "tests/expected/tree.dog.exclude")
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_threads() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-j", "0", "fox", FOX])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Invalid thread count \"0\""));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn binary_matches() -> TestResult {
+    run(&["fox", BINARY], "tests/expected/binary.dat.fox")
+}
+
+// --------------------------------------------------
+#[test]
+fn binary_as_text() -> TestResult {
+    run(&["-a", "fox", BINARY], "tests/expected/binary.dat.fox.text")?;
+    run(&["--binary-files=text", "fox", BINARY], "tests/expected/binary.dat.fox.text")
+}
+
+// --------------------------------------------------
+#[test]
+fn binary_without_match() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--binary-files=without-match", "fox", BINARY])
+        .assert()
+        .code(1)
+        .stdout("");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn binary_count() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-c", "fox", BINARY])
+        .assert()
+        .success()
+        .stdout("2\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn latin1() -> TestResult {
+    run(&["lait", "tests/latin1.txt"], "tests/expected/latin1.txt.lait")
+}
+
+// --------------------------------------------------
+#[test]
+fn search_zip() -> TestResult {
+    run(&["-rz", "--sort=path", "fox", "tests/zip"], "tests/expected/zip.fox")
+}
+
+// --------------------------------------------------
+#[test]
+fn search_zip_tar() -> TestResult {
+    run(&["-zn", "error", "tests/zip/logs.tar.gz"], "tests/expected/logs.tar.gz.error.n")
+}
+
+// --------------------------------------------------
+#[test]
+fn json() -> TestResult {
+    run(&["--json", "-B1", "dog", FOX, BUSTLE, EMPTY], "tests/expected/json.dog")
+}
+
+// --------------------------------------------------
+#[test]
+fn json_insensitive() -> TestResult {
+    run(&["--json", "-i", "nobody", NOBODY], "tests/expected/nobody.txt.json.insensitive")
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_json_count() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--json", "-c", "fox", FOX])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot be used with"));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn replace() -> TestResult {
+    run(&["--replace", "$1-$1", "-i", "(the)", BUSTLE], "tests/expected/bustle.txt.replace")
+}
+
+// --------------------------------------------------
+#[test]
+fn replace_only_matching() -> TestResult {
+    run(&["-o", "--replace", "${w}!", r"(?P<w>\w+)y", FOX], "tests/expected/fox.txt.replace.only_matching")
+}
+
+// --------------------------------------------------
+#[test]
+fn replace_dry_run() -> TestResult {
+    run(&["--in-place", "--dry-run", "--replace", "kitten", "fox", FOX, BUSTLE], "tests/expected/fox.txt.replace.dry_run")
+}
+
+// --------------------------------------------------
+#[test]
+fn replace_in_place() -> TestResult {
+    let dir = tempfile::tempdir()?;
+    let file = dir.path().join("fox.txt");
+    fs::copy(FOX, &file)?;
+    let filename = file.to_string_lossy().to_string();
+
+    Command::cargo_bin(PRG)?
+        .args(["--in-place", "--backup", ".bak", "--replace", "cat", "fox", &filename])
+        .assert()
+        .success()
+        .stdout("");
+    assert_eq!(fs::read_to_string(&file)?, "The quick brown cat jumps over the lazy dog.\n");
+    assert_eq!(fs::read_to_string(format!("{}.bak", filename))?, fs::read_to_string(FOX)?);
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+#[cfg(unix)]
+fn replace_in_place_links() -> TestResult {
+    let dir = tempfile::tempdir()?;
+    let target = dir.path().join("target.txt");
+    let symlink = dir.path().join("symlink.txt");
+    fs::write(&target, "fox\n")?;
+    std::os::unix::fs::symlink(&target, &symlink)?;
+    Command::cargo_bin(PRG)?
+        .args(["--in-place", "--replace", "cat", "fox", &symlink.to_string_lossy()])
+        .assert()
+        .success();
+    assert!(fs::symlink_metadata(&symlink)?.file_type().is_symlink());
+    assert_eq!(fs::read_to_string(&target)?, "cat\n");
+
+    let linked = dir.path().join("linked.txt");
+    fs::hard_link(&target, &linked)?;
+    Command::cargo_bin(PRG)?
+        .args(["--in-place", "--replace", "dog", "cat", &target.to_string_lossy()])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("has 2 hard links"));
+    assert_eq!(fs::read_to_string(&target)?, "cat\n");
+    Command::cargo_bin(PRG)?
+        .args(["--in-place", "--break-links", "--replace", "dog", "cat", &target.to_string_lossy()])
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(&target)?, "dog\n");
+    assert_eq!(fs::read_to_string(&linked)?, "cat\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_in_place_without_replace() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--in-place", "fox", FOX])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--replace"));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn perl_backreference() -> TestResult {
+    run(&["-P", r"(\w)\1", BUSTLE], "tests/expected/bustle.txt.perl.backref")
+}
+
+// --------------------------------------------------
+#[test]
+fn perl_invalid_utf8() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-P", "^ bar"])
+        .write_stdin(&b"caf\xe9 bar\n"[..])
+        .assert()
+        .code(1);
+    Command::cargo_bin(PRG)?
+        .args(["-P", r"(?<=\s)bar$"])
+        .write_stdin(&b"caf\xe9 bar\n"[..])
+        .assert()
+        .success()
+        .stdout(&b"caf\xe9 bar\n"[..]);
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn perl_backtrack_limit() -> TestResult {
+    let input = format!("{}b\n", "a".repeat(30));
+    Command::cargo_bin(PRG)?
+        .args(["-P", r"(a|aa)*\1c|b$"])
+        .write_stdin(input)
+        .assert()
+        .code(2)
+        .stderr(predicate::str::starts_with("-: ").and(predicate::str::contains("backtracking")));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_lookahead_without_perl() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["the(?= )", BUSTLE])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("require -P"));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn multiline() -> TestResult {
+    run(&["-Un", r"fn \w+\(\n\s+self", "tests/methods.txt"], "tests/expected/methods.txt.multiline")
+}
+
+// --------------------------------------------------
+#[test]
+fn multiline_only_matching() -> TestResult {
+    run(&["-U", "-n", "-o", r"(?s)fn \w+\(.*?\)", "tests/methods.txt"], "tests/expected/methods.txt.multiline.only_matching")
+}
+
+// --------------------------------------------------
+#[test]
+fn multiline_stdin() -> TestResult {
+    let input = fs::read_to_string("tests/methods.txt")?;
+    let expected = fs::read_to_string("tests/expected/methods.txt.multiline")?;
+    Command::cargo_bin(PRG)?
+        .args(["-Un", r"fn \w+\(\n\s+self"])
+        .write_stdin(input)
+        .assert()
+        .stdout(expected);
+    Ok(())
+}
+
+// --------------------------------------------------
+// Waits up to `secs` for the child to exit, killing it if it has not.
+fn wait_bounded(child: &mut std::process::Child, secs: u64) -> Result<Option<std::process::ExitStatus>, std::io::Error> {
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(secs);
+    while std::time::Instant::now() < deadline {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    child.kill()?;
+    child.wait()?;
+    Ok(None)
+}
+
+// --------------------------------------------------
+#[test]
+fn follow_appended_lines() -> TestResult {
+    let dir = tempfile::tempdir()?;
+    let file = dir.path().join("app.log");
+    fs::write(&file, "error: old\n")?;
+
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin(PRG))
+        .args(["--follow", "-m", "1", "error", &file.to_string_lossy()])
+        .stdout(std::process::Stdio::piped())
+        .spawn()?;
+    let stdout = child.stdout.take().unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let mut line = String::new();
+        std::io::BufRead::read_line(&mut std::io::BufReader::new(stdout), &mut line).ok();
+        tx.send(line).ok();
+    });
+
+    // Lines appended before grepr has opened the file are not followed, so
+    // keep appending until one of them comes through.
+    let mut line = None;
+    for _ in 0..40 {
+        fs::OpenOptions::new().append(true).open(&file)?.write_all(b"info: ok\nerror: new\n")?;
+        if let Ok(found) = rx.recv_timeout(std::time::Duration::from_millis(250)) {
+            line = Some(found);
+            break;
+        }
+    }
+
+    let status = wait_bounded(&mut child, 10)?;
+    assert_eq!(line.as_deref(), Some("error: new\n"));
+    assert!(status.is_some_and(|status| status.success()));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn follow_max_count_zero() -> TestResult {
+    let dir = tempfile::tempdir()?;
+    let file = dir.path().join("app.log");
+    fs::write(&file, "error: old\n")?;
+
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin(PRG))
+        .args(["--follow", "-m", "0", "error", &file.to_string_lossy()])
+        .stdout(std::process::Stdio::piped())
+        .spawn()?;
+    fs::OpenOptions::new().append(true).open(&file)?.write_all(b"error: new\n")?;
+
+    let status = wait_bounded(&mut child, 10)?;
+    assert_eq!(status.and_then(|status| status.code()), Some(1));
+    let mut stdout = String::new();
+    std::io::Read::read_to_string(&mut child.stdout.take().unwrap(), &mut stdout)?;
+    assert_eq!(stdout, "");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_follow_stdin() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--follow", "fox"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("cannot follow standard input"));
+    Ok(())
+}
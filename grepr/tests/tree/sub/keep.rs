@@ -0,0 +1 @@
+// dog rs